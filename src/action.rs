@@ -0,0 +1,321 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+// One line of a cleanup plan. `Display` renders the bash the script emits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Comment(String),
+    Remove { path: PathBuf, reason: Option<String> },
+    Rename { from: PathBuf, to: PathBuf },
+    // Metadata carried over to a promoted file (see `Preserve`)
+    Chmod { path: PathBuf, mode: u32 },
+    Touch { path: PathBuf, mtime_ns: i64 },
+    SetXattr { path: PathBuf, name: String, value: Vec<u8> },
+}
+
+impl Action {
+    pub fn remove(path: PathBuf, reason: Option<String>) -> Self {
+        Action::Remove { path, reason }
+    }
+}
+
+// `text` as one shell word. Nothing is special inside single quotes, so a
+// file name cannot run commands; an embedded quote is written as '\''.
+pub(crate) fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+pub(crate) fn quote_path(path: &Path) -> String {
+    shell_quote(&path.to_string_lossy())
+}
+
+// Comments stay on their line whatever the paths in them contain.
+fn one_line(text: &str) -> String {
+    text.replace('\n', "\\n")
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Comment(text) => write!(f, "# {}", one_line(text)),
+            Action::Remove { path, reason: Some(reason) } => {
+                write!(f, "rm {} # {}", quote_path(path), one_line(reason))
+            }
+            Action::Remove { path, reason: None } => write!(f, "rm {}", quote_path(path)),
+            Action::Rename { from, to } => {
+                write!(f, "mv {} {}", quote_path(from), quote_path(to))
+            }
+            Action::Chmod { path, mode } => write!(f, "chmod {:04o} {}", mode, quote_path(path)),
+            Action::Touch { path, mtime_ns } => {
+                write!(f, "touch -m -d {} {}", format_timestamp(*mtime_ns), quote_path(path))
+            }
+            Action::SetXattr { path, name, value } => {
+                write!(f, "setfattr -n {} -v {} {}", shell_quote(name), format_value(value), quote_path(path))
+            }
+        }
+    }
+}
+
+// Nanoseconds since the epoch as a UTC time both GNU and BSD `touch -d` read.
+fn format_timestamp(ns: i64) -> String {
+    let time = chrono::DateTime::from_timestamp(ns.div_euclid(1_000_000_000), ns.rem_euclid(1_000_000_000) as u32)
+        .unwrap_or_default();
+    time.format("%Y-%m-%dT%H:%M:%S%.9fZ").to_string()
+}
+
+fn parse_timestamp(text: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(text).ok()?.timestamp_nanos_opt()
+}
+
+// `setfattr -v` takes hex after "0x"; an empty value is written as "".
+fn format_value(value: &[u8]) -> String {
+    if value.is_empty() {
+        return "\"\"".to_string();
+    }
+    let hex: String = value.iter().map(|b| format!("{b:02x}")).collect();
+    format!("0x{hex}")
+}
+
+fn parse_value(text: &str) -> Option<Vec<u8>> {
+    if text == "\"\"" {
+        return Some(vec![]);
+    }
+    let hex = text.strip_prefix("0x")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Read back a word written by `shell_quote`, and what follows it.
+fn parse_word(text: &str) -> Option<(String, &str)> {
+    let mut word = String::new();
+    let mut rest = text.strip_prefix('\'')?;
+    loop {
+        let end = rest.find('\'')?;
+        word.push_str(&rest[..end]);
+        rest = &rest[end + 1..];
+        match rest.strip_prefix("\\''") {
+            Some(more) => {
+                word.push('\'');
+                rest = more;
+            }
+            None => return Some((word, rest)),
+        }
+    }
+}
+
+fn parse_quoted(text: &str) -> Option<(PathBuf, &str)> {
+    parse_word(text).map(|(word, rest)| (PathBuf::from(word), rest))
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    // Reads back a line as rendered by `Display`.
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Not a plan line: {}", line);
+        if let Some(text) = line.strip_prefix('#') {
+            return Ok(Action::Comment(text.strip_prefix(' ').unwrap_or(text).to_string()));
+        }
+        if let Some(rest) = line.strip_prefix("rm ") {
+            let (path, rest) = parse_quoted(rest).ok_or_else(invalid)?;
+            let reason = match rest {
+                "" => None,
+                _ => Some(rest.strip_prefix(" # ").ok_or_else(invalid)?.to_string()),
+            };
+            return Ok(Action::Remove { path, reason });
+        }
+        if let Some(rest) = line.strip_prefix("mv ") {
+            let (from, rest) = parse_quoted(rest).ok_or_else(invalid)?;
+            let (to, rest) = rest.strip_prefix(' ').and_then(parse_quoted).ok_or_else(invalid)?;
+            if !rest.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(Action::Rename { from, to });
+        }
+        if let Some(rest) = line.strip_prefix("chmod ") {
+            let (mode, rest) = rest.split_once(' ').ok_or_else(invalid)?;
+            let mode = u32::from_str_radix(mode, 8).map_err(|_| invalid())?;
+            let (path, rest) = parse_quoted(rest).ok_or_else(invalid)?;
+            return rest.is_empty().then_some(Action::Chmod { path, mode }).ok_or_else(invalid);
+        }
+        if let Some(rest) = line.strip_prefix("touch -m -d ") {
+            let (time, rest) = rest.split_once(' ').ok_or_else(invalid)?;
+            let mtime_ns = parse_timestamp(time).ok_or_else(invalid)?;
+            let (path, rest) = parse_quoted(rest).ok_or_else(invalid)?;
+            return rest.is_empty().then_some(Action::Touch { path, mtime_ns }).ok_or_else(invalid);
+        }
+        if let Some(rest) = line.strip_prefix("setfattr -n ") {
+            let (name, rest) = parse_word(rest).ok_or_else(invalid)?;
+            let (value, rest) = rest.strip_prefix(" -v ").and_then(|r| r.split_once(' ')).ok_or_else(invalid)?;
+            let value = parse_value(value).ok_or_else(invalid)?;
+            let (path, rest) = parse_quoted(rest).ok_or_else(invalid)?;
+            return rest.is_empty().then_some(Action::SetXattr { path, name, value }).ok_or_else(invalid);
+        }
+        Err(invalid())
+    }
+}
+
+// A file a script group's guard expects, from `fdup_unchanged PATH SIZE DIGEST`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guard {
+    pub path: PathBuf,
+    pub size: u64,
+    pub digest: String,
+}
+
+// Actions of a script that run together: a group's comments and the changes
+// its guards protect. Changes outside any guard have no guards.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScriptGroup {
+    pub guards: Vec<Guard>,
+    pub actions: Vec<Action>,
+}
+
+// A script printed by `plan`, read back: the hash its guard digests were made
+// with, if it says, and its groups in order.
+#[derive(Debug, Default)]
+pub struct Script {
+    pub hash: Option<String>,
+    pub groups: Vec<ScriptGroup>,
+}
+
+// "if fdup_unchanged 'a' 3 ab && fdup_unchanged 'b' 3 ab; then"
+fn parse_guards(line: &str) -> Option<Vec<Guard>> {
+    let mut rest = line.strip_prefix("if ")?.strip_suffix("; then")?;
+    let mut guards = vec![];
+    loop {
+        let (path, tail) = parse_quoted(rest.strip_prefix("fdup_unchanged ")?)?;
+        let (size, tail) = tail.strip_prefix(' ')?.split_once(' ')?;
+        let (digest, more) = match tail.split_once(" && ") {
+            Some((digest, more)) => (digest, Some(more)),
+            None => (tail, None),
+        };
+        guards.push(Guard { path, size: size.parse().ok()?, digest: digest.to_string() });
+        match more {
+            Some(more) => rest = more,
+            None => return Some(guards),
+        }
+    }
+}
+
+fn is_action_line(line: &str) -> bool {
+    ["#", "rm ", "mv ", "chmod ", "touch ", "setfattr "].iter().any(|prefix| line.starts_with(prefix))
+}
+
+// Parse a script printed by `plan` into its guarded groups. The rest of the
+// shell, such as the guard function and what runs when a guard fails, is
+// skipped.
+pub fn parse_script(text: &str) -> Result<Script, String> {
+    let mut script = Script::default();
+    let mut group = ScriptGroup::default();
+    // Inside a group's `if`, and past its `else`
+    let mut guarded = false;
+    let mut skipping = false;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        if guarded && line == "fi" {
+            script.groups.push(std::mem::take(&mut group));
+            guarded = false;
+            skipping = false;
+        } else if guarded && line == "else" {
+            skipping = true;
+        } else if skipping {
+            continue;
+        } else if !guarded && line.starts_with("if fdup_unchanged ") {
+            if group.actions.iter().any(|a| !matches!(a, Action::Comment(_))) {
+                script.groups.push(std::mem::take(&mut group));
+            }
+            group.guards = parse_guards(line).ok_or_else(|| format!("line {}: Not a guard: {}", i + 1, line))?;
+            guarded = true;
+        } else if let Some(name) = line.strip_prefix("# Hash: ") {
+            script.hash = Some(name.trim().to_string());
+        } else if is_action_line(line) {
+            group.actions.push(line.parse().map_err(|e| format!("line {}: {}", i + 1, e))?);
+        }
+    }
+    if guarded {
+        return Err("guard without a closing fi".to_string());
+    }
+    if !group.actions.is_empty() {
+        script.groups.push(group);
+    }
+    Ok(script)
+}
+
+// Parse the actions of a script printed by `plan`, ignoring its guards.
+pub fn parse_plan(text: &str) -> Result<Vec<Action>, String> {
+    Ok(parse_script(text)?.groups.into_iter().flat_map(|group| group.actions).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_round_trip() {
+        let actions = [
+            Action::Comment("group".to_string()),
+            Action::remove(PathBuf::from("/d/doc (1).pdf"), None),
+            Action::remove(PathBuf::from("/d/a \"b\".pdf"), Some("truncated copy of /d/a.pdf".to_string())),
+            Action::remove(PathBuf::from("/d/it's $(rm -rf ~) `x`.pdf"), None),
+            Action::Rename { from: PathBuf::from("/d/'.pdf"), to: PathBuf::from("/d/''.pdf") },
+            Action::Rename { from: PathBuf::from("/d/doc (2).pdf"), to: PathBuf::from("/d/doc.pdf") },
+            Action::Chmod { path: PathBuf::from("/d/doc.pdf"), mode: 0o4755 },
+            Action::Touch { path: PathBuf::from("/d/doc.pdf"), mtime_ns: 1_718_000_000_123_456_789 },
+            Action::SetXattr { path: PathBuf::from("/d/doc.pdf"), name: "user.xdg.origin.url".to_string(), value: b"http://x".to_vec() },
+            Action::SetXattr { path: PathBuf::from("/d/doc.pdf"), name: "user.empty".to_string(), value: vec![] },
+        ];
+        for action in actions {
+            assert_eq!(action.to_string().parse::<Action>(), Ok(action));
+        }
+        assert!("echo hi".parse::<Action>().is_err());
+        assert!("mv 'a'".parse::<Action>().is_err());
+        assert!("chmod 9 'a'".parse::<Action>().is_err());
+        assert!("rm 'a".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn test_paths_are_single_quoted() {
+        let action = Action::remove(PathBuf::from("/d/$HOME `id` \"it's\".pdf"), Some("a\nrm -rf /".to_string()));
+        assert_eq!(action.to_string(), "rm '/d/$HOME `id` \"it'\\''s\".pdf' # a\\nrm -rf /");
+    }
+
+    #[test]
+    fn test_parse_script_groups_guards() {
+        let text = [
+            "# Hash: blake3",
+            "fdup_unchanged() {",
+            "    if command -v b3sum > /dev/null 2>&1; then",
+            "    fi",
+            "}",
+            "# group one",
+            "if fdup_unchanged '/d/a.pdf' 3 ab && fdup_unchanged '/d/a (1).pdf' 3 ab; then",
+            "    rm '/d/a (1).pdf'",
+            "else",
+            "    printf 'Skipped %s: files changed since this script was made\\n' '/d/a.pdf' >&2",
+            "fi",
+            "rm '/d/b.pdf'",
+        ].join("\n");
+        let script = parse_script(&text).unwrap();
+        assert_eq!(script.hash.as_deref(), Some("blake3"));
+        assert_eq!(script.groups.len(), 2);
+        assert_eq!(script.groups[0].guards, vec![
+            Guard { path: PathBuf::from("/d/a.pdf"), size: 3, digest: "ab".to_string() },
+            Guard { path: PathBuf::from("/d/a (1).pdf"), size: 3, digest: "ab".to_string() },
+        ]);
+        assert_eq!(script.groups[0].actions, vec![
+            Action::Comment("group one".to_string()),
+            Action::remove(PathBuf::from("/d/a (1).pdf"), None),
+        ]);
+        assert!(script.groups[1].guards.is_empty());
+        assert!(parse_script("if fdup_unchanged '/d/a.pdf' 3 ab; then\n    rm '/d/a.pdf'").is_err());
+    }
+}
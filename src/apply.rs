@@ -0,0 +1,426 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::action::Guard;
+use crate::file_hash::{file_hash, ContentHasher};
+use crate::file_util::{rename_exchange, rename_noreplace, set_file_mode, set_modified_ns, set_xattr};
+use crate::journal::{Journal, JournalEntry};
+use crate::{Action, MyResult};
+
+// Executes plans natively instead of through bash. Removed files are moved
+// into a quarantine directory rather than deleted, and every move is written
+// to the journal so `undo_journal` can put things back.
+pub struct Applier {
+    quarantine: PathBuf,
+    // Desktop trash; the freedesktop.org default location when unset
+    trash: Option<PathBuf>,
+    journal: Journal,
+}
+
+// The freedesktop.org trash: `$XDG_DATA_HOME/Trash`, else `~/.local/share/Trash`.
+fn default_trash_dir() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .map(|data| data.join("Trash"))
+}
+
+// "TRASH/files/NAME" -> "TRASH/info/NAME.trashinfo"
+pub(crate) fn trash_info_path(trashed: &Path) -> PathBuf {
+    let name = trashed.file_name().unwrap_or_default().to_string_lossy();
+    let trash = trashed.parent().and_then(Path::parent).unwrap_or(Path::new("."));
+    trash.join("info").join(format!("{name}.trashinfo"))
+}
+
+// Trash info files hold the original path URL-encoded.
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+// Size and BLAKE3 digest of `path`, recorded so undo can tell it is moving
+// back the same file.
+fn fingerprint(path: &Path) -> io::Result<(u64, String)> {
+    let size = fs::metadata(path)?.len();
+    Ok((size, file_hash(path)?))
+}
+
+// The first name for `path` in `dir` that is not `taken`: "doc.pdf",
+// "1-doc.pdf", "2-doc.pdf", ...
+fn free_name<F>(dir: &Path, path: &Path, taken: F) -> PathBuf
+where
+    F: Fn(&Path) -> bool,
+{
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut candidate = dir.join(&name);
+    let mut n = 1;
+    while taken(&candidate) {
+        candidate = dir.join(format!("{n}-{name}"));
+        n += 1;
+    }
+    candidate
+}
+
+impl Applier {
+    pub fn new(quarantine: &Path, journal: Journal) -> MyResult<Self> {
+        fs::create_dir_all(quarantine)
+            .map_err(|e| format!("Failed to create quarantine {}: {}", quarantine.display(), e))?;
+        Ok(Applier { quarantine: quarantine.to_path_buf(), trash: None, journal })
+    }
+
+    pub fn with_trash(mut self, trash: &Path) -> Self {
+        self.trash = Some(trash.to_path_buf());
+        self
+    }
+
+    pub fn journal_path(&self) -> &Path {
+        self.journal.path()
+    }
+
+    // Move `path` into quarantine.
+    pub fn remove(&mut self, path: &Path) -> MyResult<()> {
+        self.quarantine_as(path, path)
+    }
+
+    // Move `path` into quarantine under the file name of `original`.
+    fn quarantine_as(&mut self, path: &Path, original: &Path) -> MyResult<()> {
+        let (size, digest) = fingerprint(path)
+            .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        let quarantined = free_name(&self.quarantine, original, |p| p.symlink_metadata().is_ok());
+        rename_noreplace(path, &quarantined)
+            .map_err(|e| format!("Failed to move {} to {}: {}", path.display(), quarantined.display(), e))?;
+        self.journal.record(&JournalEntry::Remove { path: path.to_path_buf(), quarantined, size, digest })?;
+        Ok(())
+    }
+
+    // Move `path` to the desktop trash, with the info file that lets a file
+    // manager restore it.
+    pub fn trash(&mut self, path: &Path) -> MyResult<()> {
+        self.trash_as(path, path)
+    }
+
+    // Move `path` to the desktop trash as though it had been `original`.
+    fn trash_as(&mut self, path: &Path, original: &Path) -> MyResult<()> {
+        let trash = self.trash.clone().or_else(default_trash_dir)
+            .ok_or("Cannot locate the trash directory")?;
+        let (size, digest) = fingerprint(path)
+            .map_err(|e| format!("Failed to trash {}: {}", path.display(), e))?;
+        let original = std::path::absolute(original)?;
+        let files = trash.join("files");
+        fs::create_dir_all(&files)?;
+        fs::create_dir_all(trash.join("info"))?;
+
+        let trashed = free_name(&files, &original, |p| {
+            p.symlink_metadata().is_ok() || trash_info_path(p).exists()
+        });
+        let info_path = trash_info_path(&trashed);
+        let mut info = OpenOptions::new().write(true).create_new(true).open(&info_path)
+            .map_err(|e| format!("Failed to create {}: {}", info_path.display(), e))?;
+        let deleted = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S");
+        writeln!(info, "[Trash Info]\nPath={}\nDeletionDate={}", percent_encode(&original), deleted)?;
+
+        if let Err(e) = rename_noreplace(path, &trashed) {
+            let _ = fs::remove_file(&info_path);
+            return Err(format!("Failed to move {} to {}: {}", path.display(), trashed.display(), e).into());
+        }
+        self.journal.record(&JournalEntry::Trash { path: path.to_path_buf(), trashed, size, digest })?;
+        Ok(())
+    }
+
+    // Never replaces an existing `to`.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> MyResult<()> {
+        let (size, digest) = fingerprint(from)
+            .map_err(|e| format!("Failed to rename {}: {}", from.display(), e))?;
+        rename_noreplace(from, to)
+            .map_err(|e| format!("Failed to rename {} to {}: {}", from.display(), to.display(), e))?;
+        self.journal.record(&JournalEntry::Rename { from: from.to_path_buf(), to: to.to_path_buf(), size, digest })?;
+        Ok(())
+    }
+
+    // Put `keeper` in the place of `base`, sending the old base to the trash
+    // or quarantine. The two files are swapped atomically first, so `base`
+    // always names one of them; only then is the old base, now at the
+    // keeper's path, moved away. Where the filesystem cannot swap, the base
+    // is moved away before the rename instead.
+    pub fn promote(&mut self, keeper: &Path, base: &Path, trash: bool) -> MyResult<()> {
+        let fingerprint_of = |path: &Path| {
+            fingerprint(path).map_err(|e| format!("Failed to promote {}: {}", keeper.display(), e))
+        };
+        let (keeper_size, keeper_digest) = fingerprint_of(keeper)?;
+        let (base_size, base_digest) = fingerprint_of(base)?;
+        match rename_exchange(keeper, base) {
+            Ok(()) => {
+                self.journal.record(&JournalEntry::Exchange {
+                    from: keeper.to_path_buf(),
+                    to: base.to_path_buf(),
+                    from_size: keeper_size,
+                    to_size: base_size,
+                    from_digest: keeper_digest,
+                    to_digest: base_digest,
+                })?;
+                if trash { self.trash_as(keeper, base) } else { self.quarantine_as(keeper, base) }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                if trash { self.trash(base)? } else { self.remove(base)? }
+                self.rename(keeper, base)
+            }
+            Err(e) => Err(format!("Failed to swap {} and {}: {}", keeper.display(), base.display(), e).into()),
+        }
+    }
+
+    // `applied` is called after each action succeeds, so a caller's log
+    // matches what actually happened even when a later action fails. A
+    // removal whose path a later rename takes over is done as a `promote`.
+    pub fn apply<F>(&mut self, actions: &[Action], mut applied: F) -> MyResult<()>
+    where
+        F: FnMut(&Action),
+    {
+        let targets: HashSet<&Path> = actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::Rename { to, .. } => Some(to.as_path()),
+                _ => None,
+            })
+            .collect();
+        let mut replaced: HashMap<&Path, &Action> = HashMap::new();
+        for action in actions {
+            match action {
+                Action::Comment(_) => continue,
+                Action::Remove { path, .. } if targets.contains(path.as_path()) => {
+                    replaced.insert(path, action);
+                    continue;
+                }
+                Action::Remove { path, .. } => self.remove(path)?,
+                Action::Rename { from, to } => match replaced.remove(to.as_path()) {
+                    Some(removal) => {
+                        self.promote(from, to, false)?;
+                        applied(removal);
+                    }
+                    None => self.rename(from, to)?,
+                },
+                Action::Chmod { path, mode } => set_file_mode(path, *mode)
+                    .map_err(|e| format!("Failed to set the mode of {}: {}", path.display(), e))?,
+                Action::Touch { path, mtime_ns } => set_modified_ns(path, *mtime_ns)
+                    .map_err(|e| format!("Failed to set the mtime of {}: {}", path.display(), e))?,
+                Action::SetXattr { path, name, value } => set_xattr(path, name, value)
+                    .map_err(|e| format!("Failed to set {} on {}: {}", name, path.display(), e))?,
+            }
+            applied(action);
+        }
+        // Removals no rename followed up on
+        for (path, action) in replaced {
+            self.remove(path)?;
+            applied(action);
+        }
+        Ok(())
+    }
+}
+
+// Problems that would stop `actions` from applying cleanly to the files as
+// they are now. An empty result means the plan is still valid.
+pub fn check_plan(actions: &[Action]) -> Vec<String> {
+    // Paths created or removed by earlier actions of the plan
+    let mut state: HashMap<&Path, bool> = HashMap::new();
+    let exists = |state: &HashMap<&Path, bool>, path: &Path| {
+        state.get(path).copied().unwrap_or_else(|| path.is_file())
+    };
+    let mut problems = vec![];
+    for action in actions {
+        match action {
+            Action::Comment(_) => {}
+            Action::Remove { path, .. } => {
+                if !exists(&state, path) {
+                    problems.push(format!("{} no longer exists", path.display()));
+                }
+                state.insert(path, false);
+            }
+            Action::Rename { from, to } => {
+                if !exists(&state, from) {
+                    problems.push(format!("{} no longer exists", from.display()));
+                }
+                if exists(&state, to) {
+                    problems.push(format!("{} would be overwritten", to.display()));
+                }
+                state.insert(from, false);
+                state.insert(to, true);
+            }
+            Action::Chmod { path, .. } | Action::Touch { path, .. } | Action::SetXattr { path, .. } => {
+                if !exists(&state, path) {
+                    problems.push(format!("{} no longer exists", path.display()));
+                }
+            }
+        }
+    }
+    problems
+}
+
+// Why a script group's guards no longer hold, if they do not: as in the
+// script itself, every guarded file must still have its planned size and
+// digest.
+pub fn check_guards(guards: &[Guard], hasher: &dyn ContentHasher) -> Option<String> {
+    guards.iter().find_map(|guard| {
+        let path = &guard.path;
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                if metadata.len() != guard.size {
+                    return Some(format!("{} changed size", path.display()));
+                }
+            }
+            _ => return Some(format!("{} no longer exists", path.display())),
+        }
+        match hasher.hash_file(path) {
+            Ok(digest) if digest == guard.digest => None,
+            Ok(_) => Some(format!("{} has different content", path.display())),
+            Err(e) => Some(format!("{} cannot be hashed: {}", path.display(), e)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{read_journal, undo_journal};
+    use tempfile::TempDir;
+
+    fn applier(temp_dir: &TempDir) -> Applier {
+        let quarantine = temp_dir.path().join("quarantine");
+        let journal = Journal::open(&temp_dir.path().join("journal.log")).unwrap();
+        Applier::new(&quarantine, journal).unwrap()
+    }
+
+    #[test]
+    fn test_apply_then_undo() {
+        let temp_dir = TempDir::new().unwrap();
+        let base = temp_dir.path().join("doc.pdf");
+        let copy = temp_dir.path().join("doc (1).pdf");
+        fs::write(&base, b"old").unwrap();
+        fs::write(&copy, b"newer").unwrap();
+
+        let actions = vec![
+            Action::Comment("group".to_string()),
+            Action::remove(base.clone(), None),
+            Action::Rename { from: copy.clone(), to: base.clone() },
+        ];
+        let mut applier = applier(&temp_dir);
+        let mut log = vec![];
+        applier.apply(&actions, |a| log.push(a.to_string())).unwrap();
+
+        assert_eq!(fs::read(&base).unwrap(), b"newer");
+        assert!(!copy.exists());
+        assert_eq!(log.len(), 2);
+        assert_eq!(fs::read(temp_dir.path().join("quarantine/doc.pdf")).unwrap(), b"old");
+        let journal = read_journal(applier.journal_path()).unwrap();
+        assert_eq!(journal.len(), 2);
+        assert!(matches!(journal[0], JournalEntry::Exchange { from_size: 5, to_size: 3, .. }));
+
+        assert_eq!(undo_journal(applier.journal_path(), |_| {}).unwrap(), 2);
+        assert_eq!(fs::read(&base).unwrap(), b"old");
+        assert_eq!(fs::read(&copy).unwrap(), b"newer");
+    }
+
+    #[test]
+    fn test_apply_stops_at_first_failure() {
+        let temp_dir = TempDir::new().unwrap();
+        let missing = temp_dir.path().join("missing.pdf");
+        let other = temp_dir.path().join("other.pdf");
+        fs::write(&other, b"x").unwrap();
+
+        let actions = vec![
+            Action::remove(missing, None),
+            Action::remove(other.clone(), None),
+        ];
+        let mut applier = applier(&temp_dir);
+        let mut log = vec![];
+        assert!(applier.apply(&actions, |a| log.push(a.to_string())).is_err());
+
+        assert!(other.exists());
+        assert!(log.is_empty());
+        assert!(read_journal(applier.journal_path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rename_never_replaces() {
+        let temp_dir = TempDir::new().unwrap();
+        let base = temp_dir.path().join("doc.pdf");
+        let copy = temp_dir.path().join("doc (1).pdf");
+        fs::write(&base, b"base").unwrap();
+        fs::write(&copy, b"copy").unwrap();
+
+        let mut applier = applier(&temp_dir);
+        assert!(applier.rename(&copy, &base).is_err());
+        assert_eq!(fs::read(&base).unwrap(), b"base");
+        assert_eq!(fs::read(&copy).unwrap(), b"copy");
+    }
+
+    #[test]
+    fn test_check_plan() {
+        let temp_dir = TempDir::new().unwrap();
+        let base = temp_dir.path().join("doc.pdf");
+        let copy = temp_dir.path().join("doc (1).pdf");
+        fs::write(&base, b"old").unwrap();
+        fs::write(&copy, b"new").unwrap();
+
+        let promote = vec![
+            Action::remove(base.clone(), None),
+            Action::Rename { from: copy.clone(), to: base.clone() },
+        ];
+        assert!(check_plan(&promote).is_empty());
+
+        let clobber = vec![Action::Rename { from: copy.clone(), to: base.clone() }];
+        assert_eq!(check_plan(&clobber), vec![format!("{} would be overwritten", base.display())]);
+
+        fs::remove_file(&copy).unwrap();
+        assert_eq!(check_plan(&promote), vec![format!("{} no longer exists", copy.display())]);
+    }
+
+    #[test]
+    fn test_quarantine_names_do_not_collide() {
+        let temp_dir = TempDir::new().unwrap();
+        let first = temp_dir.path().join("a/doc.pdf");
+        let second = temp_dir.path().join("b/doc.pdf");
+        for path in [&first, &second] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"x").unwrap();
+        }
+
+        let mut applier = applier(&temp_dir);
+        applier.apply(&[Action::remove(first, None), Action::remove(second, None)], |_| {}).unwrap();
+
+        assert!(temp_dir.path().join("quarantine/doc.pdf").exists());
+        assert!(temp_dir.path().join("quarantine/1-doc.pdf").exists());
+    }
+
+    #[test]
+    fn test_trash_writes_info_and_undo_clears_it() {
+        let temp_dir = TempDir::new().unwrap();
+        let trash = temp_dir.path().join("Trash");
+        let file = temp_dir.path().join("my doc.pdf");
+        fs::write(&file, b"x").unwrap();
+
+        let mut applier = applier(&temp_dir).with_trash(&trash);
+        applier.trash(&file).unwrap();
+
+        assert!(!file.exists());
+        assert!(trash.join("files/my doc.pdf").exists());
+        let info = fs::read_to_string(trash.join("info/my doc.pdf.trashinfo")).unwrap();
+        assert!(info.starts_with("[Trash Info]\nPath=/"));
+        assert!(info.contains("my%20doc.pdf\nDeletionDate="));
+
+        undo_journal(applier.journal_path(), |_| {}).unwrap();
+        assert!(file.exists());
+        assert!(!trash.join("info/my doc.pdf.trashinfo").exists());
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    io::{self, BufReader},
+    path::Path,
+};
+
+use zip::ZipArchive;
+
+use crate::throttle::PacedFile;
+use crate::MyResult;
+
+// Containers that are ZIP files underneath. Re-downloads of these often
+// differ only in entry timestamps or entry order.
+const ARCHIVE_EXTENSIONS: &[&str] = &[
+    ".zip", ".docx", ".xlsx", ".pptx", ".epub", ".odt", ".ods", ".odp", ".jar",
+];
+
+pub fn is_archive_extension(ext: &str) -> bool {
+    ARCHIVE_EXTENSIONS.iter().any(|a| a.eq_ignore_ascii_case(ext))
+}
+
+// Digest of an archive's members rather than its bytes: each member's name and
+// decompressed content, in name order. Directory entries and per-entry
+// metadata (timestamps, compression level, order) do not contribute.
+pub fn archive_digest(path: &Path) -> MyResult<String> {
+    let file = PacedFile::open(path)?;
+    let mut archive = ZipArchive::new(BufReader::new(file))
+        .map_err(|e| format!("Failed to read archive {}: {}", path.display(), e))?;
+
+    let mut members: Vec<(String, blake3::Hash)> = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)
+            .map_err(|e| format!("Failed to read entry {} of {}: {}", i, path.display(), e))?;
+        if entry.is_dir() {
+            continue;
+        }
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut entry, &mut hasher)?;
+        members.push((entry.name().to_owned(), hasher.finalize()));
+    }
+    members.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hasher = blake3::Hasher::new();
+    for (name, hash) in &members {
+        hasher.update(name.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(hash.as_bytes());
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, io::Write};
+    use tempfile::TempDir;
+    use zip::{write::SimpleFileOptions, CompressionMethod, DateTime, ZipWriter};
+
+    fn write_zip(path: &Path, entries: &[(&str, &str)], options: SimpleFileOptions) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        for (name, content) in entries {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_is_archive_extension() {
+        assert!(is_archive_extension(".zip"));
+        assert!(is_archive_extension(".DOCX"));
+        assert!(!is_archive_extension(".pdf"));
+    }
+
+    #[test]
+    fn test_reordered_and_retimestamped_archives_are_equivalent() {
+        let temp_dir = TempDir::new().unwrap();
+        let a = temp_dir.path().join("a.zip");
+        let b = temp_dir.path().join("b.zip");
+        write_zip(&a, &[("one.txt", "1"), ("two.txt", "2")], SimpleFileOptions::default());
+        write_zip(
+            &b,
+            &[("two.txt", "2"), ("one.txt", "1")],
+            SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .last_modified_time(DateTime::from_date_and_time(2001, 2, 3, 4, 5, 6).unwrap()),
+        );
+
+        assert_ne!(std::fs::read(&a).unwrap(), std::fs::read(&b).unwrap());
+        assert_eq!(archive_digest(&a).unwrap(), archive_digest(&b).unwrap());
+    }
+
+    #[test]
+    fn test_different_members_are_not_equivalent() {
+        let temp_dir = TempDir::new().unwrap();
+        let a = temp_dir.path().join("a.zip");
+        let b = temp_dir.path().join("b.zip");
+        write_zip(&a, &[("one.txt", "1")], SimpleFileOptions::default());
+        write_zip(&b, &[("one.txt", "changed")], SimpleFileOptions::default());
+
+        assert_ne!(archive_digest(&a).unwrap(), archive_digest(&b).unwrap());
+    }
+
+    #[test]
+    fn test_non_archive_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("not.zip");
+        std::fs::write(&path, b"plain text").unwrap();
+
+        assert!(archive_digest(&path).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    fs::{self, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::file_hash::{ContentHasher, Digester};
+use crate::file_util::file_id;
+use crate::MyResult;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS files (
+        path       TEXT PRIMARY KEY,
+        size       INTEGER NOT NULL,
+        mtime_ns   INTEGER NOT NULL,
+        created_ns INTEGER,
+        dev        INTEGER NOT NULL,
+        inode      INTEGER NOT NULL,
+        algorithm  TEXT NOT NULL,
+        digest     TEXT NOT NULL,
+        scanned_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS files_digest ON files (algorithm, digest);
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub created_ns: Option<i64>,
+    pub dev: u64,
+    pub inode: u64,
+    pub digest: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogGroup {
+    pub digest: String,
+    pub size: u64,
+    pub paths: Vec<PathBuf>,
+    // Distinct files behind `paths`; hardlinked names count once
+    pub inodes: u64,
+}
+
+impl CatalogGroup {
+    // Bytes freed by keeping one file of the group
+    pub fn reclaimable(&self) -> u64 {
+        self.size * self.inodes.saturating_sub(1)
+    }
+}
+
+fn nanos(time: io::Result<SystemTime>) -> Option<i64> {
+    let since_epoch = time.ok()?.duration_since(UNIX_EPOCH).ok()?;
+    i64::try_from(since_epoch.as_nanos()).ok()
+}
+
+fn dev_inode(metadata: &Metadata) -> (u64, u64) {
+    file_id(metadata).unwrap_or((0, 0))
+}
+
+// Catalog rows are keyed by absolute path so the database can be shared
+// between runs started from different directories.
+fn catalog_key(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("catalog: {}", e))
+}
+
+// Persistent record of scanned files and their digests.
+pub struct Catalog {
+    conn: Mutex<Connection>,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> MyResult<Self> {
+        let conn = Connection::open(path)
+            .map_err(|e| format!("Failed to open catalog {}: {}", path.display(), e))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Catalog { conn: Mutex::new(conn) })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, path: &Path) -> rusqlite::Result<Option<(CatalogEntry, String)>> {
+        let key = catalog_key(path);
+        self.lock()
+            .query_row(
+                "SELECT size, mtime_ns, created_ns, dev, inode, digest, algorithm
+                 FROM files WHERE path = ?1",
+                params![key.to_string_lossy()],
+                |row| {
+                    Ok((
+                        CatalogEntry {
+                            path: key.clone(),
+                            size: row.get::<_, i64>(0)? as u64,
+                            mtime_ns: row.get(1)?,
+                            created_ns: row.get(2)?,
+                            dev: row.get::<_, i64>(3)? as u64,
+                            inode: row.get::<_, i64>(4)? as u64,
+                            digest: row.get(5)?,
+                        },
+                        row.get(6)?,
+                    ))
+                },
+            )
+            .optional()
+    }
+
+    pub fn upsert(&self, entry: &CatalogEntry, algorithm: &str) -> rusqlite::Result<()> {
+        let scanned_at = nanos(Ok(SystemTime::now())).unwrap_or(0);
+        self.lock().execute(
+            "INSERT INTO files (path, size, mtime_ns, created_ns, dev, inode, algorithm, digest, scanned_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (path) DO UPDATE SET
+                size = excluded.size, mtime_ns = excluded.mtime_ns,
+                created_ns = excluded.created_ns, dev = excluded.dev,
+                inode = excluded.inode, algorithm = excluded.algorithm,
+                digest = excluded.digest, scanned_at = excluded.scanned_at",
+            params![
+                entry.path.to_string_lossy(),
+                entry.size as i64,
+                entry.mtime_ns,
+                entry.created_ns,
+                entry.dev as i64,
+                entry.inode as i64,
+                algorithm,
+                entry.digest,
+                scanned_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Drop rows below `root` whose files no longer exist.
+    pub fn forget_missing(&self, root: &Path) -> MyResult<usize> {
+        let key = catalog_key(root);
+        let prefix = format!("{}/", key.to_string_lossy().trim_end_matches('/'));
+        // Every path that starts with "dir/" sorts before "dir0"
+        let end = format!("{}0", &prefix[..prefix.len() - 1]);
+        let conn = self.lock();
+        let paths: Vec<String> = conn
+            .prepare("SELECT path FROM files WHERE path >= ?1 AND path < ?2")?
+            .query_map(params![prefix, end], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut removed = 0;
+        for path in paths.iter().filter(|p| !Path::new(p).exists()) {
+            removed += conn.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+        }
+        Ok(removed)
+    }
+
+    // Files that share a digest, largest reclaimable space first. Names that
+    // are hardlinks of one file are not duplicates of each other.
+    pub fn duplicate_groups(&self, algorithm: &str) -> MyResult<Vec<CatalogGroup>> {
+        let conn = self.lock();
+        // Where the platform reports no inode, every path is its own file
+        let mut stmt = conn.prepare(
+            "SELECT f.digest, f.size, f.path, d.inodes
+             FROM files f
+             JOIN (SELECT digest, inodes, size * (inodes - 1) AS reclaimable
+                   FROM (SELECT digest, MAX(size) AS size,
+                                COUNT(DISTINCT CASE WHEN inode = 0 THEN path ELSE dev || ':' || inode END) AS inodes
+                         FROM files WHERE algorithm = ?1 GROUP BY digest)
+                   WHERE inodes > 1) d ON d.digest = f.digest
+             WHERE f.algorithm = ?1
+             ORDER BY d.reclaimable DESC, f.digest, f.path",
+        )?;
+        let rows = stmt.query_map(params![algorithm], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)? as u64,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)? as u64,
+            ))
+        })?;
+
+        let mut groups: Vec<CatalogGroup> = vec![];
+        for row in rows {
+            let (digest, size, path, inodes) = row?;
+            match groups.last_mut() {
+                Some(group) if group.digest == digest => group.paths.push(PathBuf::from(path)),
+                _ => groups.push(CatalogGroup { digest, size, paths: vec![PathBuf::from(path)], inodes }),
+            }
+        }
+        Ok(groups)
+    }
+}
+
+// Reuses a catalogued digest when the file's size, mtime and inode are
+// unchanged; otherwise hashes with `fallback` and records the result.
+pub struct CatalogHasher {
+    catalog: Arc<Catalog>,
+    fallback: Arc<dyn ContentHasher>,
+}
+
+impl CatalogHasher {
+    pub fn new(catalog: Arc<Catalog>, fallback: Arc<dyn ContentHasher>) -> Self {
+        CatalogHasher { catalog, fallback }
+    }
+}
+
+impl CatalogHasher {
+    // The catalog row `path` would get, without a digest yet.
+    fn entry(&self, path: &Path) -> io::Result<CatalogEntry> {
+        let metadata = fs::metadata(path)?;
+        let (dev, inode) = dev_inode(&metadata);
+        Ok(CatalogEntry {
+            path: catalog_key(path),
+            size: metadata.len(),
+            mtime_ns: nanos(metadata.modified()).unwrap_or(0),
+            created_ns: nanos(metadata.created()),
+            dev,
+            inode,
+            digest: String::new(),
+        })
+    }
+
+    // The catalogued digest of `entry`, if its file has not changed since.
+    fn cached(&self, path: &Path, entry: &CatalogEntry) -> io::Result<Option<String>> {
+        Ok(match self.catalog.get(path).map_err(sql_error)? {
+            Some((cached, algorithm))
+                if algorithm == self.name()
+                    && cached.size == entry.size
+                    && cached.mtime_ns == entry.mtime_ns
+                    && cached.dev == entry.dev
+                    && cached.inode == entry.inode => Some(cached.digest),
+            _ => None,
+        })
+    }
+}
+
+impl ContentHasher for CatalogHasher {
+    fn name(&self) -> &'static str {
+        self.fallback.name()
+    }
+
+    fn hash_file(&self, path: &Path) -> io::Result<String> {
+        let mut entry = self.entry(path)?;
+        if let Some(digest) = self.cached(path, &entry)? {
+            return Ok(digest);
+        }
+        entry.digest = self.fallback.hash_file(path)?;
+        self.catalog.upsert(&entry, self.name()).map_err(sql_error)?;
+        Ok(entry.digest)
+    }
+
+    // Files the catalog knows, or that cannot be examined, go to `hash_file`.
+    fn digester(&self, path: &Path) -> Option<Box<dyn Digester>> {
+        let entry = self.entry(path).ok()?;
+        match self.cached(path, &entry) {
+            Ok(None) => self.fallback.digester(path),
+            _ => None,
+        }
+    }
+
+    fn record(&self, path: &Path, digest: &str) -> io::Result<()> {
+        let entry = CatalogEntry { digest: digest.to_string(), ..self.entry(path)? };
+        self.catalog.upsert(&entry, self.name()).map_err(sql_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_hash::HashAlgorithm;
+    use crate::manifest::hash_files;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_catalog_caches_unchanged_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let catalog = Arc::new(Catalog::open(&temp_dir.path().join("cat.db")).unwrap());
+        let file = temp_dir.path().join("doc.pdf");
+        fs::write(&file, b"content").unwrap();
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let counting = Arc::new(|p: &Path| -> io::Result<String> {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            HashAlgorithm::Blake3.hasher().hash_file(p)
+        });
+        let hasher = CatalogHasher::new(catalog.clone(), counting);
+
+        let first = hasher.hash_file(&file).unwrap();
+        let second = hasher.hash_file(&file).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+        let (entry, _) = catalog.get(&file).unwrap().unwrap();
+        assert_eq!(entry.size, 7);
+        assert_eq!(entry.digest, first);
+        assert!(hasher.digester(&file).is_none());
+
+        // Digests computed by the pipeline are recorded too
+        let other = temp_dir.path().join("other.pdf");
+        fs::write(&other, b"other").unwrap();
+        let hasher = CatalogHasher::new(catalog.clone(), HashAlgorithm::Blake3.hasher());
+        let (entries, errors) = hash_files(&[file.clone(), other.clone()], &hasher);
+        assert!(errors.is_empty());
+        assert_eq!(catalog.get(&other).unwrap().unwrap().0.digest, entries[1].1);
+    }
+
+    #[test]
+    fn test_duplicate_groups_and_forget_missing() {
+        let temp_dir = TempDir::new().unwrap();
+        let catalog = Arc::new(Catalog::open(&temp_dir.path().join("cat.db")).unwrap());
+        let hasher = CatalogHasher::new(catalog.clone(), HashAlgorithm::Blake3.hasher());
+        for (name, content) in [("a.pdf", "same"), ("b.pdf", "same"), ("c.pdf", "other")] {
+            let path = temp_dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            hasher.hash_file(&path).unwrap();
+        }
+        // A second name for "c.pdf" is not a duplicate
+        fs::hard_link(temp_dir.path().join("c.pdf"), temp_dir.path().join("d.pdf")).unwrap();
+        hasher.hash_file(&temp_dir.path().join("d.pdf")).unwrap();
+
+        let groups = catalog.duplicate_groups("blake3").unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].paths.len(), 2);
+        assert_eq!(groups[0].reclaimable(), 4);
+
+        fs::remove_file(temp_dir.path().join("b.pdf")).unwrap();
+        assert_eq!(catalog.forget_missing(temp_dir.path()).unwrap(), 1);
+        assert!(catalog.duplicate_groups("blake3").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Bandwidth, Deduplicator, DeduplicatorBuilder, HashAlgorithm, KeepPolicy, MyResult, Preserve, DEFAULT_COPY_PATTERN};
+
+// One `[profile.NAME]` section of the config file. Fields left out fall back
+// to the defaults, and the command line can override any of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub roots: Vec<PathBuf>,
+    pub filetypes: Vec<String>,
+    pub copy_patterns: Vec<String>,
+    pub excludes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep: Option<KeepPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<HashAlgorithm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_case: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve: Option<Preserve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follow_symlinks: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_file_system: Option<bool>,
+    // Reader and hashing threads; chosen automatically when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_threads: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_threads: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nice: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bwlimit: Option<Bandwidth>,
+}
+
+fn pick<T: Clone>(overrides: &[T], base: &[T]) -> Vec<T> {
+    if overrides.is_empty() { base.to_vec() } else { overrides.to_vec() }
+}
+
+impl Profile {
+    // Every field `overrides` sets replaces the one in `self`. Lists are
+    // replaced whole rather than appended to.
+    pub fn merge(&self, overrides: &Profile) -> Profile {
+        Profile {
+            roots: pick(&overrides.roots, &self.roots),
+            filetypes: pick(&overrides.filetypes, &self.filetypes),
+            copy_patterns: pick(&overrides.copy_patterns, &self.copy_patterns),
+            excludes: pick(&overrides.excludes, &self.excludes),
+            keep: overrides.keep.or(self.keep),
+            hash: overrides.hash.or(self.hash),
+            ignore_case: overrides.ignore_case.or(self.ignore_case),
+            preserve: overrides.preserve.or(self.preserve),
+            follow_symlinks: overrides.follow_symlinks.or(self.follow_symlinks),
+            one_file_system: overrides.one_file_system.or(self.one_file_system),
+            io_threads: overrides.io_threads.or(self.io_threads),
+            hash_threads: overrides.hash_threads.or(self.hash_threads),
+            nice: overrides.nice.or(self.nice),
+            bwlimit: overrides.bwlimit.or(self.bwlimit),
+        }
+    }
+
+    // The profile with the built-in defaults filled in wherever it is unset.
+    pub fn resolved(&self) -> Profile {
+        let defaults = Profile {
+            roots: vec![PathBuf::from(".")],
+            filetypes: vec![".pdf".to_string()],
+            copy_patterns: vec![DEFAULT_COPY_PATTERN.to_string()],
+            excludes: vec![],
+            keep: Some(KeepPolicy::default()),
+            hash: Some(HashAlgorithm::default()),
+            ignore_case: Some(false),
+            preserve: Some(Preserve::default()),
+            follow_symlinks: Some(false),
+            one_file_system: Some(false),
+            io_threads: None,
+            hash_threads: None,
+            nice: Some(false),
+            bwlimit: None,
+        };
+        defaults.merge(self)
+    }
+
+    pub fn builder(&self) -> DeduplicatorBuilder {
+        let mut builder = Deduplicator::builder()
+            .keep(self.keep.unwrap_or_default())
+            .hash_algorithm(self.hash.unwrap_or_default())
+            .ignore_case(self.ignore_case.unwrap_or_default())
+            .preserve(self.preserve.unwrap_or_default())
+            .follow_symlinks(self.follow_symlinks.unwrap_or_default())
+            .one_file_system(self.one_file_system.unwrap_or_default())
+            .nice(self.nice.unwrap_or_default());
+        if let Some(limit) = self.bwlimit {
+            builder = builder.bandwidth_limit(limit);
+        }
+        if let Some(threads) = self.io_threads {
+            builder = builder.io_threads(threads);
+        }
+        if let Some(threads) = self.hash_threads {
+            builder = builder.hash_threads(threads);
+        }
+        for root in &self.roots {
+            builder = builder.root(root);
+        }
+        for ext in &self.filetypes {
+            builder = builder.filetype(ext);
+        }
+        for pattern in &self.copy_patterns {
+            builder = builder.copy_pattern(pattern);
+        }
+        for pattern in &self.excludes {
+            builder = builder.exclude(pattern);
+        }
+        builder
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    // `$XDG_CONFIG_HOME/file-dup/config.toml`, else `~/.config/file-dup/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_home.join("file-dup").join("config.toml"))
+    }
+
+    pub fn load(path: &Path) -> MyResult<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    pub fn profile(&self, name: &str) -> MyResult<&Profile> {
+        self.profile.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.profile.keys().map(|k| k.as_str()).collect();
+            format!("Unknown profile '{}' (defined: {})", name, known.join(", ")).into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [profile.downloads]
+        roots = ["/home/me/Downloads"]
+        filetypes = [".pdf", ".epub"]
+        keep = "oldest"
+        preserve = "mode,times"
+        nice = true
+        bwlimit = "50M"
+
+        [profile.photos]
+        roots = ["/home/me/Pictures"]
+        filetypes = [".jpg"]
+        excludes = ["thumb*"]
+        hash = "xxh3"
+        ignore_case = true
+        io_threads = 1
+        one_file_system = true
+    "#;
+
+    #[test]
+    fn test_profiles_merge_with_overrides() {
+        let config = Config::parse(CONFIG).unwrap();
+        let downloads = config.profile("downloads").unwrap();
+
+        let overrides = Profile { filetypes: vec![".zip".to_string()], ..Profile::default() };
+        let effective = downloads.merge(&overrides).resolved();
+        assert_eq!(effective.roots, vec![PathBuf::from("/home/me/Downloads")]);
+        assert_eq!(effective.filetypes, vec![".zip".to_string()]);
+        assert_eq!(effective.keep, Some(KeepPolicy::Oldest));
+        assert_eq!(effective.preserve, Some(Preserve { mode: true, times: true, xattrs: false }));
+        assert_eq!(effective.hash, Some(HashAlgorithm::Blake3));
+        assert_eq!(effective.copy_patterns, vec![DEFAULT_COPY_PATTERN.to_string()]);
+
+        assert_eq!(config.profile("photos").unwrap().hash, Some(HashAlgorithm::Xxh3));
+        assert_eq!(config.profile("photos").unwrap().ignore_case, Some(true));
+        assert_eq!(effective.ignore_case, Some(false));
+        assert_eq!(effective.follow_symlinks, Some(false));
+        assert_eq!(config.profile("photos").unwrap().one_file_system, Some(true));
+        assert_eq!(config.profile("photos").unwrap().io_threads, Some(1));
+        assert_eq!(effective.io_threads, None);
+        assert_eq!(effective.nice, Some(true));
+        assert_eq!(effective.bwlimit, Some(Bandwidth(50 << 20)));
+        let err = config.profile("music").unwrap_err().to_string();
+        assert!(err.contains("defined: downloads, photos"));
+    }
+
+    #[test]
+    fn test_rejects_unknown_settings() {
+        assert!(Config::parse("[profile.x]\nroot = \".\"\n").is_err());
+        assert!(Config::parse("[profile.x]\nkeep = \"largest\"\n").is_err());
+    }
+}
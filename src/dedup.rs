@@ -0,0 +1,1387 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use glob::{MatchOptions, Pattern};
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::archive::{archive_digest, is_archive_extension};
+use crate::error::{copy_io_error, DedupError};
+use crate::file_hash::{ContentHasher, HashAlgorithm};
+use crate::file_util::{file_id, get_creation_time, is_strict_prefix, is_symlink_loop, link_count};
+use crate::pipeline::HashPipeline;
+use crate::preserve::Preserve;
+use crate::throttle::{self, Bandwidth, PacedReads, Throttle};
+use crate::partial::{plan_partials, PARTIAL_SUFFIXES};
+use crate::{files_matching_pattern_with, Action, MyResult};
+
+// The suffix browsers give a repeated download: "doc (1).pdf"
+pub const DEFAULT_COPY_PATTERN: &str = r" \(\d+\)";
+
+// Digests computed ahead of planning, or why they could not be, by path
+type Digests = HashMap<PathBuf, io::Result<String>>;
+
+// Which member of a group survives when the copies differ from the base file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeepPolicy {
+    // Most recently created copy, renamed to the base name
+    #[default]
+    Newest,
+    // Earliest-created member, which may be the base file itself
+    Oldest,
+    // The base file, whatever its copies contain
+    Base,
+}
+
+impl FromStr for KeepPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(KeepPolicy::Newest),
+            "oldest" => Ok(KeepPolicy::Oldest),
+            "base" => Ok(KeepPolicy::Base),
+            _ => Err(format!("Unknown keep policy '{}' (expected newest, oldest or base)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupMember {
+    pub path: PathBuf,
+    pub digest: String,
+}
+
+// A base file and its copies. `members` starts with the base file or, when
+// `base` does not exist, with the copy standing in for it.
+#[derive(Debug, Clone)]
+pub struct DuplicateGroup {
+    pub base: PathBuf,
+    pub members: Vec<GroupMember>,
+    pub actions: Vec<Action>,
+    // Copies that are hardlinks of the base file. Removing them would free
+    // nothing, so they are left alone.
+    pub linked: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct DedupReport {
+    pub files: Vec<PathBuf>,
+    pub groups: Vec<DuplicateGroup>,
+    pub partial_downloads: Vec<Action>,
+    // Groups that could not be planned, one error each. Their files are left alone.
+    pub errors: Vec<DedupError>,
+    // Files left alone for reasons that are not errors
+    pub warnings: Vec<String>,
+}
+
+impl DedupReport {
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.groups
+            .iter()
+            .flat_map(|g| g.actions.iter())
+            .chain(self.partial_downloads.iter())
+    }
+
+    // Bytes freed by carrying out every planned removal. A hardlinked file
+    // only frees its space once every one of its names is removed.
+    pub fn reclaimable(&self) -> u64 {
+        let mut total = 0;
+        // (size, names, names removed) of each hardlinked file
+        let mut links: HashMap<(u64, u64), (u64, u64, u64)> = HashMap::new();
+        for action in self.actions() {
+            let Action::Remove { path, .. } = action else {
+                continue;
+            };
+            let Ok(metadata) = fs::metadata(path) else {
+                continue;
+            };
+            match file_id(&metadata) {
+                Some(id) if link_count(&metadata) > 1 => {
+                    links.entry(id).or_insert((metadata.len(), link_count(&metadata), 0)).2 += 1;
+                }
+                _ => total += metadata.len(),
+            }
+        }
+        total + links.values()
+            .filter(|(_, names, removed)| removed >= names)
+            .map(|(size, _, _)| size)
+            .sum::<u64>()
+    }
+
+    // Copies that were already hardlinks of their base file.
+    pub fn linked(&self) -> impl Iterator<Item = &PathBuf> {
+        self.groups.iter().flat_map(|g| g.linked.iter())
+    }
+}
+
+#[derive(Default)]
+pub struct DeduplicatorBuilder {
+    roots: Vec<PathBuf>,
+    filetypes: Vec<String>,
+    excludes: Vec<String>,
+    copy_patterns: Vec<String>,
+    keep: KeepPolicy,
+    hasher: Option<Arc<dyn ContentHasher>>,
+    threads: Option<usize>,
+    pipeline: HashPipeline,
+    fail_fast: bool,
+    ignore_case: bool,
+    preserve: Preserve,
+    follow_symlinks: bool,
+    one_file_system: bool,
+    bandwidth_limit: Option<Bandwidth>,
+    nice: bool,
+}
+
+impl DeduplicatorBuilder {
+    // Directory to scan. May be given more than once; defaults to ".".
+    pub fn root(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.roots.push(dir.into());
+        self
+    }
+
+    // File extension including the dot, e.g. ".pdf". May be given more than once.
+    pub fn filetype(mut self, ext: impl Into<String>) -> Self {
+        self.filetypes.push(ext.into());
+        self
+    }
+
+    // Glob matched against file names; matching files are ignored.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.excludes.push(pattern.into());
+        self
+    }
+
+    // Regex for the suffix that marks a copy, between the base stem and the
+    // extension. Replaces the default " (N)" pattern once given.
+    pub fn copy_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.copy_patterns.push(pattern.into());
+        self
+    }
+
+    pub fn keep(mut self, policy: KeepPolicy) -> Self {
+        self.keep = policy;
+        self
+    }
+
+    // Content digest used to compare files. Defaults to BLAKE3.
+    pub fn hasher(mut self, hasher: Arc<dyn ContentHasher>) -> Self {
+        self.hasher = Some(hasher);
+        self
+    }
+
+    pub fn hash_algorithm(self, algorithm: HashAlgorithm) -> Self {
+        self.hasher(algorithm.hasher())
+    }
+
+    // Worker threads for planning groups once their files are hashed.
+    // Defaults to a count based on the workload.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    // Threads reading files to hash. Defaults to 4.
+    pub fn io_threads(mut self, threads: usize) -> Self {
+        self.pipeline.io_threads = Some(threads);
+        self
+    }
+
+    // Threads hashing what the readers read. Defaults to one per CPU.
+    pub fn hash_threads(mut self, threads: usize) -> Self {
+        self.pipeline.hash_threads = Some(threads);
+        self
+    }
+
+    // Abort the run on the first file error instead of collecting errors
+    // in the report.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    // Match extensions and base names regardless of case, so `Report (1).PDF`
+    // is a copy of `report.pdf`.
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    // Metadata a file renamed over its base takes from the group.
+    pub fn preserve(mut self, preserve: Preserve) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    // Scan the files symlinks point to. Off by default, when symlinks are
+    // left alone.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    // Skip files on a different file system than the directory they are
+    // found in, e.g. behind a bind mount or a followed symlink.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    // Cap the combined rate at which a run reads files. Unlimited by default.
+    pub fn bandwidth_limit(mut self, limit: Bandwidth) -> Self {
+        self.bandwidth_limit = Some(limit);
+        self
+    }
+
+    // Run as a background job: the threads a run starts get the lowest CPU
+    // and I/O priority, and the files it reads are dropped from the page cache.
+    pub fn nice(mut self, nice: bool) -> Self {
+        self.nice = nice;
+        self
+    }
+
+    pub fn build(self) -> MyResult<Deduplicator> {
+        if self.filetypes.is_empty() {
+            return Err("At least one file type is required".into());
+        }
+        for ext in &self.filetypes {
+            if !ext.starts_with('.') {
+                return Err(format!("File extension must start with a dot (e.g., '.pdf'), got '{}'", ext).into());
+            }
+        }
+        for root in &self.roots {
+            if !root.is_dir() {
+                return Err(format!("Path is not a directory: {}", root.display()).into());
+            }
+        }
+
+        let excludes = self.excludes
+            .iter()
+            .map(|p| Pattern::new(p).map_err(|e| format!("Invalid exclude pattern '{}': {}", p, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let copy_patterns = if self.copy_patterns.is_empty() {
+            vec![DEFAULT_COPY_PATTERN.to_owned()]
+        } else {
+            self.copy_patterns
+        };
+        for pattern in &copy_patterns {
+            Regex::new(pattern)
+                .map_err(|e| format!("Invalid copy pattern '{}': {}", pattern, e))?;
+        }
+
+        let roots = if self.roots.is_empty() {
+            vec![PathBuf::from(".")]
+        } else {
+            self.roots
+        };
+
+        Ok(Deduplicator {
+            roots,
+            filetypes: self.filetypes,
+            excludes,
+            // Each pattern was validated above
+            copy_suffix: copy_suffix_regex(&copy_patterns, self.ignore_case)
+                .map_err(|e| format!("Invalid copy pattern: {}", e))?,
+            keep: self.keep,
+            hasher: self.hasher.unwrap_or_else(|| HashAlgorithm::default().hasher()),
+            threads: self.threads,
+            pipeline: self.pipeline,
+            fail_fast: self.fail_fast,
+            ignore_case: self.ignore_case,
+            preserve: self.preserve,
+            follow_symlinks: self.follow_symlinks,
+            one_file_system: self.one_file_system,
+            throttle: Arc::new(Throttle::new(self.bandwidth_limit, self.nice)),
+        })
+    }
+}
+
+pub struct Deduplicator {
+    roots: Vec<PathBuf>,
+    filetypes: Vec<String>,
+    excludes: Vec<Pattern>,
+    // Matches exactly one copy suffix
+    copy_suffix: Regex,
+    keep: KeepPolicy,
+    hasher: Arc<dyn ContentHasher>,
+    threads: Option<usize>,
+    pipeline: HashPipeline,
+    fail_fast: bool,
+    ignore_case: bool,
+    preserve: Preserve,
+    follow_symlinks: bool,
+    one_file_system: bool,
+    // Paces every read of a run
+    throttle: Arc<Throttle>,
+}
+
+impl Deduplicator {
+    pub fn builder() -> DeduplicatorBuilder {
+        DeduplicatorBuilder::default()
+    }
+
+    // Pace the reads of the calling thread like a run's, until the result is
+    // dropped, for work done alongside one such as updating a catalog.
+    pub fn pace_reads(&self) -> PacedReads {
+        self.throttle.enter()
+    }
+
+    // Every file the run would look at, without hashing anything.
+    pub fn scan(&self) -> MyResult<Vec<PathBuf>> {
+        let mut files = vec![];
+        for root in &self.roots {
+            for ext in &self.filetypes {
+                files.extend(self.files_in(root, ext)?.0);
+            }
+        }
+        Ok(files)
+    }
+
+    // The files in `root` with extension `ext` that can be scanned, and a
+    // warning for each one that was left alone.
+    pub(crate) fn files_in(&self, root: &Path, ext: &str) -> MyResult<(Vec<PathBuf>, Vec<String>)> {
+        let dir = root.to_str()
+            .ok_or_else(|| format!("Path contains invalid UTF-8: {}", root.display()))?;
+        let options = MatchOptions { case_sensitive: !self.ignore_case, ..MatchOptions::new() };
+        let files = files_matching_pattern_with(dir, &format!("*{ext}"), options)?
+            .into_iter()
+            .filter(|p| !self.is_excluded(p))
+            .collect();
+        Ok(self.regular_files(root, files))
+    }
+
+    // Leftover partial downloads of `ext` files in `root`, picked by the same
+    // rules as the files scanned, and a warning for each one left alone.
+    pub(crate) fn partial_downloads(&self, root: &Path, ext: &str) -> MyResult<(Vec<Action>, Vec<String>)> {
+        let mut partials = vec![];
+        let mut warnings = vec![];
+        for suffix in PARTIAL_SUFFIXES {
+            let (files, skipped) = self.files_in(root, &format!("{ext}{suffix}"))?;
+            partials.extend(files);
+            warnings.extend(skipped);
+        }
+        Ok((plan_partials(partials), warnings))
+    }
+
+    // Only regular files are scanned: opening a FIFO blocks forever, and a
+    // symlink hashed as its target could be removed as a copy of it. Followed
+    // symlinks that loop, or lead to a file scanned under another name, are
+    // left alone too. A file that cannot be examined is kept, so hashing it
+    // reports the error.
+    fn regular_files(&self, root: &Path, files: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<String>) {
+        let root_dev = fs::metadata(root).ok().and_then(|m| file_id(&m)).map(|(dev, _)| dev);
+        let unusual = |path: &Path, metadata: &fs::Metadata| -> Option<String> {
+            if !metadata.is_file() {
+                return Some(format!("\"{}\" is not a regular file and was left alone", path.display()));
+            }
+            let dev = file_id(metadata).map(|(dev, _)| dev);
+            (self.one_file_system && dev != root_dev)
+                .then(|| format!("\"{}\" is on another file system and was left alone", path.display()))
+        };
+
+        let mut kept = vec![];
+        let mut links = vec![];
+        let mut warnings = vec![];
+        // File behind each scanned name, to catch symlinks to one of them
+        let mut scanned: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        for file in files {
+            let metadata = match fs::symlink_metadata(&file) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    links.push(file);
+                    continue;
+                }
+                Ok(metadata) => metadata,
+                Err(_) => {
+                    kept.push(file);
+                    continue;
+                }
+            };
+            if let Some(warning) = unusual(&file, &metadata) {
+                warnings.push(warning);
+                continue;
+            }
+            if let Some(id) = file_id(&metadata) {
+                scanned.entry(id).or_insert_with(|| file.clone());
+            }
+            kept.push(file);
+        }
+
+        for link in links {
+            if !self.follow_symlinks {
+                warnings.push(format!("\"{}\" is a symlink and was left alone", link.display()));
+                continue;
+            }
+            let metadata = match fs::metadata(&link) {
+                Ok(metadata) => metadata,
+                Err(e) if is_symlink_loop(&e) => {
+                    warnings.push(format!("\"{}\" is a symlink loop and was left alone", link.display()));
+                    continue;
+                }
+                Err(_) => {
+                    kept.push(link);
+                    continue;
+                }
+            };
+            if let Some(warning) = unusual(&link, &metadata) {
+                warnings.push(warning);
+                continue;
+            }
+            if let Some(id) = file_id(&metadata) {
+                if let Some(target) = scanned.get(&id) {
+                    warnings.push(format!(
+                        "\"{}\" links to \"{}\", which is scanned already, and was left alone",
+                        link.display(), target.display()
+                    ));
+                    continue;
+                }
+                scanned.insert(id, link.clone());
+            }
+            kept.push(link);
+        }
+        kept.sort();
+        (kept, warnings)
+    }
+
+    pub fn hasher(&self) -> &dyn ContentHasher {
+        self.hasher.as_ref()
+    }
+
+    pub fn run(&self) -> MyResult<DedupReport> {
+        let _paced = self.pace_reads();
+        let mut report = DedupReport::default();
+        for root in &self.roots {
+            for ext in &self.filetypes {
+                let (files, skipped) = self.files_in(root, ext)?;
+                let (candidates, warnings) = self.without_case_twins(&files);
+                let (groups, errors) = self.find_groups(&candidates, ext)?;
+                report.warnings.extend(skipped);
+                report.warnings.extend(warnings);
+                report.groups.extend(groups);
+                report.errors.extend(errors);
+                let (partials, skipped) = self.partial_downloads(root, ext)?;
+                report.partial_downloads.extend(partials);
+                report.warnings.extend(skipped);
+                report.files.extend(files);
+            }
+        }
+        Ok(report)
+    }
+
+    // The name as it is compared: lowercased when ignoring case.
+    fn fold(&self, name: &str) -> String {
+        if self.ignore_case { name.to_lowercase() } else { name.to_string() }
+    }
+
+    // When ignoring case, files in one folder whose names differ only by case
+    // cannot be told apart, so they are left out with a warning.
+    fn without_case_twins(&self, files: &[PathBuf]) -> (Vec<PathBuf>, Vec<String>) {
+        if !self.ignore_case {
+            return (files.to_vec(), vec![]);
+        }
+        let mut by_name: BTreeMap<PathBuf, Vec<&PathBuf>> = BTreeMap::new();
+        for file in files {
+            by_name.entry(PathBuf::from(file.to_string_lossy().to_lowercase())).or_default().push(file);
+        }
+        let mut warnings = vec![];
+        let mut kept = vec![];
+        for twins in by_name.into_values() {
+            if let [file] = twins[..] {
+                kept.push(file.clone());
+            } else {
+                let names: Vec<String> = twins.iter().map(|p| format!("\"{}\"", p.display())).collect();
+                warnings.push(format!("{} differ only by case and were left alone", names.join(" and ")));
+            }
+        }
+        (kept, warnings)
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+        self.excludes.iter().any(|p| p.matches(&name))
+    }
+
+    fn find_groups(&self, files: &[PathBuf], ext: &str) -> MyResult<(Vec<DuplicateGroup>, Vec<DedupError>)> {
+        if files.is_empty() {
+            return Ok((vec![], vec![]));
+        }
+
+        // Set optimal thread count based on CPU cores and workload
+        let thread_count = self.threads.unwrap_or_else(|| {
+            std::cmp::min(num_cpus::get(), std::cmp::max(1, files.len() / 10))
+        });
+
+        let digests = self.prefetch(files, ext);
+        let pacing = throttle::current();
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .start_handler(move |_| Throttle::adopt(pacing.clone()))
+            .build()
+            .map_err(|e| format!("Failed to build thread pool: {}", e))?
+            .install(|| self.find_groups_parallel(files, ext, &digests))
+    }
+
+    // Hash every file that will be compared with another up front, through
+    // the pipeline, so planning does not wait on the disk. Files that fail
+    // keep their error, which is reported when they are planned.
+    fn prefetch(&self, files: &[PathBuf], ext: &str) -> Digests {
+        let key = |file: &Path, stem: &str| (file.parent().map(Path::to_path_buf), self.fold(stem));
+        let bases: HashSet<_> = files.iter().filter_map(|f| Some(key(f, self.file_stem(f, ext)?))).collect();
+        let mut copies: HashMap<_, usize> = HashMap::new();
+        for file in files {
+            if let Some(stem) = self.copy_base_stem(file, ext) {
+                *copies.entry(key(file, stem)).or_default() += 1;
+            }
+        }
+
+        // Copies with a base file or another copy, and bases with copies
+        let compared: Vec<PathBuf> = files
+            .iter()
+            .filter(|file| match self.copy_base_stem(file, ext) {
+                Some(stem) => {
+                    let key = key(file, stem);
+                    bases.contains(&key) || copies[&key] > 1
+                }
+                None => self.file_stem(file, ext).is_some_and(|stem| copies.contains_key(&key(file, stem))),
+            })
+            .cloned()
+            .collect();
+        compared
+            .iter()
+            .zip(self.pipeline.run(&compared, self.hasher()))
+            .map(|(file, digest)| (file.clone(), digest))
+            .collect()
+    }
+
+    fn find_groups_parallel(&self, files: &[PathBuf], ext: &str, digests: &Digests) -> MyResult<(Vec<DuplicateGroup>, Vec<DedupError>)> {
+        // Create a lookup table for faster file stem access
+        let file_stems: Vec<_> = files.iter()
+            .map(|path| {
+                self.fold(path.file_stem().and_then(|s| s.to_str()).unwrap_or(""))
+            })
+            .collect();
+
+        // Calculate chunk size based on number of files and available CPUs
+        let chunk_size = std::cmp::max(
+            1,
+            files.len() / rayon::current_num_threads().max(1)
+        );
+
+        let results: Vec<Result<Option<DuplicateGroup>, DedupError>> = files.par_iter()
+            .enumerate()
+            .with_min_len(chunk_size) // Adaptive chunk size
+            .map(|(path_idx, path)| {
+                let prefix = &file_stems[path_idx];
+
+                // Pre-filter the files to avoid repeated regex matching
+                let candidates: Vec<PathBuf> = files.iter()
+                    .enumerate()
+                    .filter(|(idx, _)| *idx != path_idx && file_stems[*idx].starts_with(prefix))
+                    .map(|(_, pb)| pb.clone())
+                    .collect();
+
+                self.plan_group_with(path, ext, &candidates, digests)
+            })
+            .collect();
+        let orphans: Vec<Result<Option<DuplicateGroup>, DedupError>> = self.orphan_copies(files, ext)
+            .into_par_iter()
+            .map(|(base, copies)| self.plan_orphans_with(&base, ext, copies, digests).map(Some))
+            .collect();
+
+        let mut groups = vec![];
+        let mut errors = vec![];
+        for result in results.into_iter().chain(orphans) {
+            match result {
+                Ok(Some(group)) => groups.push(group),
+                Ok(None) => {}
+                Err(e) if self.fail_fast => return Err(e.into()),
+                Err(e) => errors.push(e),
+            }
+        }
+        Ok((groups, errors))
+    }
+
+    fn hash(&self, path: &Path, digests: &Digests) -> Result<String, DedupError> {
+        match digests.get(path) {
+            Some(Ok(digest)) => Ok(digest.clone()),
+            Some(Err(e)) => Err(DedupError::hash(path, copy_io_error(e))),
+            None => self.hasher.hash_file(path).map_err(|e| DedupError::hash(path, e)),
+        }
+    }
+
+    // Decide what to do with `path` and its copies among `all_files`.
+    // Returns None when `path` has no copies.
+    pub fn plan_group(&self, path: &Path, ext: &str, all_files: &[PathBuf]) -> Result<Option<DuplicateGroup>, DedupError> {
+        self.plan_group_with(path, ext, all_files, &Digests::new())
+    }
+
+    fn plan_group_with(
+        &self,
+        path: &Path,
+        ext: &str,
+        all_files: &[PathBuf],
+        digests: &Digests,
+    ) -> Result<Option<DuplicateGroup>, DedupError> {
+        let name = path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| self.split_ext(n, ext).or_else(|| path.file_stem()?.to_str()))
+            .ok_or_else(|| DedupError::invalid_name(path))?;
+        let name = self.fold(name);
+        let files: Vec<PathBuf> = all_files
+            .iter()
+            .filter(|p: &&PathBuf| {
+                if p.as_path() == path || p.parent() != path.parent() {
+                    return false;
+                }
+                self.copy_base_stem(p, ext).is_some_and(|base| self.fold(base) == name)
+            })
+            .cloned()
+            .collect();
+
+        if files.is_empty() {
+            return Ok(None);
+        }
+        self.plan_members(path, path, files, ext, digests).map(Some)
+    }
+
+    // Copies among `files` whose base file does not exist, keyed by the base
+    // path they would have. Only bases with two or more copies are returned.
+    fn orphan_copies(&self, files: &[PathBuf], ext: &str) -> Vec<(PathBuf, Vec<PathBuf>)> {
+        let names: HashSet<String> = files.iter().map(|f| self.fold(&f.to_string_lossy())).collect();
+        // Keyed by the base path as compared, so copies differing in case meet
+        let mut orphans: BTreeMap<String, (PathBuf, Vec<PathBuf>)> = BTreeMap::new();
+        for file in files {
+            let Some(name) = file.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(base_stem) = self.copy_base_stem(file, ext) else {
+                continue;
+            };
+            // The extension as the copy spells it
+            let base = file.with_file_name(format!("{}{}", base_stem, &name[name.len() - ext.len()..]));
+            let key = self.fold(&base.to_string_lossy());
+            if !names.contains(&key) && base.symlink_metadata().is_err() {
+                orphans.entry(key).or_insert_with(|| (base, vec![])).1.push(file.clone());
+            }
+        }
+        orphans.into_values().filter(|(_, copies)| copies.len() > 1).collect()
+    }
+
+    // "doc.pdf" -> "doc", when the name has the extension `ext`.
+    fn split_ext<'a>(&self, name: &'a str, ext: &str) -> Option<&'a str> {
+        let at = name.len().checked_sub(ext.len())?;
+        let found = name.get(at..)?;
+        let matches = if self.ignore_case { found.eq_ignore_ascii_case(ext) } else { found == ext };
+        matches.then(|| &name[..at])
+    }
+
+    // "dir/doc.pdf" -> "doc", when the name has the extension `ext`.
+    fn file_stem<'a>(&self, path: &'a Path, ext: &str) -> Option<&'a str> {
+        self.split_ext(path.file_name()?.to_str()?, ext)
+    }
+
+    // The stem of the file `path` is a copy of: "doc (1) (2).pdf" -> "doc".
+    fn copy_base_stem<'a>(&self, path: &'a Path, ext: &str) -> Option<&'a str> {
+        let name = path.file_name()?.to_str()?;
+        base_stem(self.split_ext(name, ext)?, &self.copy_suffix)
+    }
+
+    // The file type and base stem of a numbered copy of one of the file
+    // types: "doc (1).pdf" -> (".pdf", "doc").
+    fn copy_of<'a>(&self, path: &'a Path) -> Option<(&str, &'a str)> {
+        self.filetypes.iter().find_map(|ext| Some((ext.as_str(), self.copy_base_stem(path, ext)?)))
+    }
+
+    // The file type of `path` when it is a numbered copy of that type.
+    pub fn copy_type(&self, path: &Path) -> Option<&str> {
+        self.copy_of(path).map(|(ext, _)| ext)
+    }
+
+    // The file among `files` that `copy` is a numbered copy of, and the file
+    // type they share. None when `copy` is not a copy or its base is missing.
+    pub fn base_of(&self, copy: &Path, files: &[PathBuf]) -> Option<(PathBuf, &str)> {
+        let (ext, stem) = self.copy_of(copy)?;
+        let stem = self.fold(stem);
+        files.iter()
+            .find(|p| p.parent() == copy.parent() && self.file_stem(p, ext).is_some_and(|s| self.fold(s) == stem))
+            .map(|base| (base.clone(), ext))
+    }
+
+    // Plan copies of a `base` that no longer exists. One copy stands in for
+    // the base file: the first by name under the base policy, the oldest
+    // otherwise. Identical copies are collapsed into it and the survivor is
+    // renamed to the base name.
+    pub fn plan_orphans(&self, base: &Path, ext: &str, copies: Vec<PathBuf>) -> Result<DuplicateGroup, DedupError> {
+        self.plan_orphans_with(base, ext, copies, &Digests::new())
+    }
+
+    fn plan_orphans_with(
+        &self,
+        base: &Path,
+        ext: &str,
+        mut copies: Vec<PathBuf>,
+        digests: &Digests,
+    ) -> Result<DuplicateGroup, DedupError> {
+        copies.sort();
+        if self.keep != KeepPolicy::Base {
+            let mut created = Vec::with_capacity(copies.len());
+            for copy in copies {
+                created.push((creation_time(&copy)?, copy));
+            }
+            created.sort();
+            copies = created.into_iter().map(|(_, copy)| copy).collect();
+        }
+        let anchor = copies.remove(0);
+        let mut group = self.plan_members(base, &anchor, copies, ext, digests)?;
+        group.actions.insert(1, Action::Comment(format!("{} is missing", base.display())));
+        Ok(group)
+    }
+
+    // Plan `path` and its copies `files`, leaving the surviving file at
+    // `target`. `path` is the base file the copies are compared with; it is
+    // `target` itself except in orphan groups.
+    fn plan_members(
+        &self,
+        target: &Path,
+        path: &Path,
+        files: Vec<PathBuf>,
+        ext: &str,
+        digests: &Digests,
+    ) -> Result<DuplicateGroup, DedupError> {
+        let mut result: Vec<Action> = vec![];
+        let mut members: Vec<GroupMember> = vec![];
+
+        let orig_hash: String = self.hash(path, digests)?;
+        result.push(Action::Comment(
+            format!("{} {} {}", "-".repeat(30), path.display(), orig_hash)
+        ));
+        members.push(GroupMember { path: path.to_path_buf(), digest: orig_hash.clone() });
+
+        let mut differing: Vec<PathBuf> = vec![];
+        let mut linked: Vec<PathBuf> = vec![];
+        // Archive member digest of the base file, computed on first need
+        let mut orig_archive: Option<Option<String>> = None;
+
+        // Names sharing an inode share a digest, which is computed only once
+        let file_id_of = |p: &Path| fs::metadata(p).ok().and_then(|m| file_id(&m));
+        let base_id = file_id_of(path);
+        let mut by_inode: HashMap<(u64, u64), String> = base_id.map(|id| (id, orig_hash.clone())).into_iter().collect();
+
+        for file_path in files {
+            let id = file_id_of(&file_path);
+            if id.is_some() && id == base_id {
+                result.push(Action::Comment(
+                    format!("{} is already linked to {}", file_path.display(), path.display())
+                ));
+                members.push(GroupMember { path: file_path.clone(), digest: orig_hash.clone() });
+                linked.push(file_path);
+                continue;
+            }
+            let copy_hash: String = match id.and_then(|id| by_inode.get(&id)) {
+                Some(digest) => digest.clone(),
+                None => self.hash(&file_path, digests)?,
+            };
+            if let Some(id) = id {
+                by_inode.insert(id, copy_hash.clone());
+            }
+            result.push(Action::Comment(
+                format!("{} {}", file_path.display(), copy_hash)
+            ));
+            members.push(GroupMember { path: file_path.clone(), digest: copy_hash.clone() });
+            if copy_hash == orig_hash {
+                result.push(Action::remove(
+                    file_path, Some(path.display().to_string())
+                ));
+            } else if is_archive_extension(ext)
+                && archive_equivalent(path, &file_path, &mut orig_archive)
+            {
+                result.push(Action::remove(
+                    file_path, Some(format!("{} (same archive members)", path.display()))
+                ));
+            } else {
+                differing.push(file_path);
+            }
+        }
+
+        // A member that is a strict byte prefix of another member is an
+        // interrupted download. It goes in favor of the complete file no
+        // matter which of the two is newer.
+        let truncated_of = |short: &Path| -> Result<Option<PathBuf>, DedupError> {
+            for long in std::iter::once(path).chain(differing.iter().map(PathBuf::as_path)) {
+                if long != short && is_strict_prefix(short, long)
+                    .map_err(|e| DedupError::io(short, e))?
+                {
+                    return Ok(Some(long.to_path_buf()));
+                }
+            }
+            Ok(None)
+        };
+        let base_complete = truncated_of(path)?;
+        let mut survivors: Vec<(SystemTime, PathBuf)> = vec![];
+        for file_path in &differing {
+            if let Some(complete) = truncated_of(file_path)? {
+                result.push(Action::remove(
+                    file_path.clone(), Some(format!("truncated copy of {}", complete.display()))
+                ));
+                continue;
+            }
+            survivors.push((creation_time(file_path)?, file_path.clone()));
+        }
+
+        // Most recently created first
+        survivors.sort_by(|a, b| b.cmp(a));
+
+        if survivors.is_empty() {
+            if path != target {
+                result.push(Action::Rename { from: path.to_path_buf(), to: target.to_path_buf() });
+            }
+            return self.finish_group(target, path, members, result, linked);
+        }
+
+        // A truncated base is never kept, whatever the policy says.
+        let keeper: PathBuf = match (self.keep, &base_complete) {
+            (KeepPolicy::Base, None) => path.to_path_buf(),
+            (KeepPolicy::Oldest, None) => {
+                let base_time = creation_time(path)?;
+                let (oldest_time, oldest) = &survivors[survivors.len() - 1];
+                if base_time <= *oldest_time { path.to_path_buf() } else { oldest.clone() }
+            }
+            (KeepPolicy::Oldest, Some(_)) => survivors[survivors.len() - 1].1.clone(),
+            _ => survivors[0].1.clone(),
+        };
+
+        if keeper == path {
+            for (_, other) in survivors {
+                result.push(Action::remove(other, None));
+            }
+            if path != target {
+                result.push(Action::Rename { from: keeper, to: target.to_path_buf() });
+            }
+        } else {
+            result.push(Action::remove(
+                path.to_path_buf(),
+                base_complete.map(|complete| format!("truncated copy of {}", complete.display())),
+            ));
+            for (_, other) in survivors.into_iter().filter(|(_, p)| *p != keeper) {
+                result.push(Action::remove(other, None));
+            }
+            result.push(Action::Rename { from: keeper, to: target.to_path_buf() });
+        }
+
+        self.finish_group(target, path, members, result, linked)
+    }
+
+    // When a file is renamed to `target`, give it the metadata `--preserve`
+    // asks for from the `original` it replaces and the other members.
+    fn finish_group(
+        &self,
+        target: &Path,
+        original: &Path,
+        members: Vec<GroupMember>,
+        mut actions: Vec<Action>,
+        linked: Vec<PathBuf>,
+    ) -> Result<DuplicateGroup, DedupError> {
+        if !self.preserve.is_empty() && actions.iter().any(|a| matches!(a, Action::Rename { .. })) {
+            let paths: Vec<&Path> = members.iter().map(|m| m.path.as_path()).collect();
+            let metadata = self.preserve.actions(original, &paths, target)
+                .map_err(|e| DedupError::io(original, e))?;
+            actions.extend(metadata);
+        }
+        Ok(DuplicateGroup { base: target.to_path_buf(), members, actions, linked })
+    }
+}
+
+// A regex matching exactly one copy suffix of any of `patterns`.
+pub(crate) fn copy_suffix_regex(patterns: &[String], ignore_case: bool) -> Result<Regex, regex::Error> {
+    let flag = if ignore_case { "(?i)" } else { "" };
+    Regex::new(&format!("{flag}^(?:{})$", patterns.join("|")))
+}
+
+// Strip every copy suffix from the end of `stem`: "doc (1)", "doc (1) (1)"
+// and "doc (1)(2)" all give "doc". The first suffix must match as written;
+// later ones may leave out the space it starts with. None when `stem` is not
+// a copy name.
+pub(crate) fn base_stem<'a>(stem: &'a str, suffix: &Regex) -> Option<&'a str> {
+    // Where each stripped suffix started, and whether it matched as written
+    let mut cuts: Vec<(usize, bool)> = vec![];
+    let mut head = stem;
+    'strip: loop {
+        // Shortest suffix first, and one matching as written before one
+        // that only matches with the space put back
+        for exact in [true, false] {
+            for (at, _) in head.char_indices().rev().filter(|(at, _)| *at > 0) {
+                let tail = &head[at..];
+                let found = if exact { suffix.is_match(tail) } else { suffix.is_match(&format!(" {tail}")) };
+                if found {
+                    cuts.push((at, exact));
+                    head = &head[..at];
+                    continue 'strip;
+                }
+            }
+        }
+        break;
+    }
+    while cuts.last().is_some_and(|(_, exact)| !exact) {
+        cuts.pop();
+    }
+    cuts.last().map(|(at, _)| &stem[..*at])
+}
+
+fn creation_time(path: &Path) -> Result<SystemTime, DedupError> {
+    get_creation_time(path).map_err(|e| DedupError::timestamp(path, e))
+}
+
+// Archives that cannot be read as ZIP files are simply treated as different.
+fn archive_equivalent(orig: &Path, copy: &Path, orig_archive: &mut Option<Option<String>>) -> bool {
+    let orig_digest = orig_archive.get_or_insert_with(|| archive_digest(orig).ok());
+    match (orig_digest, archive_digest(copy).ok()) {
+        (Some(a), Some(b)) => *a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn write(path: &Path, content: &str) {
+        fs::write(path, content).unwrap();
+        // Keep creation times distinct between files
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_builder_rejects_extension_without_dot() {
+        assert!(Deduplicator::builder().filetype("pdf").build().is_err());
+        assert!(Deduplicator::builder().build().is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_bad_copy_pattern() {
+        let result = Deduplicator::builder().filetype(".pdf").copy_pattern("(").build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_run_with_real_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        write(&dir_path.join("test1.pdf"), "content1");
+        write(&dir_path.join("test2.pdf"), "content2");
+        write(&dir_path.join("doc.pdf"), "same");
+        write(&dir_path.join("doc (1).pdf"), "same");
+
+        let report = Deduplicator::builder()
+            .root(dir_path)
+            .filetype(".pdf")
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(report.files.len(), 4);
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].base, dir_path.join("doc.pdf"));
+        assert_eq!(report.groups[0].members.len(), 2);
+        assert_eq!(
+            report.actions().filter(|a| matches!(a, Action::Remove { .. })).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_run_empty_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let report = Deduplicator::builder()
+            .root(temp_dir.path())
+            .filetype(".pdf")
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert!(report.files.is_empty());
+        assert!(report.groups.is_empty());
+    }
+
+    #[test]
+    fn test_exclude_and_custom_copy_pattern() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        write(&dir_path.join("doc.pdf"), "same");
+        write(&dir_path.join("doc_copy2.pdf"), "same");
+        write(&dir_path.join("keep.pdf"), "same");
+        write(&dir_path.join("keep_copy1.pdf"), "same");
+
+        let report = Deduplicator::builder()
+            .root(dir_path)
+            .filetype(".pdf")
+            .copy_pattern(r"_copy\d+")
+            .exclude("keep*")
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(
+            report.groups[0].actions.last(),
+            Some(&Action::remove(dir_path.join("doc_copy2.pdf"), Some(dir_path.join("doc.pdf").display().to_string())))
+        );
+    }
+
+    #[test]
+    fn test_orphan_copies_are_collapsed_and_renamed() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let first = dir_path.join("doc (1).pdf");
+        let second = dir_path.join("doc (2).pdf");
+        let newest = dir_path.join("doc (3).pdf");
+        write(&first, "same");
+        write(&second, "same");
+        write(&newest, "newer");
+        write(&dir_path.join("lone (1).pdf"), "alone");
+
+        let report = Deduplicator::builder().root(dir_path).filetype(".pdf").build().unwrap().run().unwrap();
+        assert_eq!(report.groups.len(), 1);
+        let group = &report.groups[0];
+        assert_eq!(group.base, dir_path.join("doc.pdf"));
+        assert_eq!(group.members[0].path, first);
+        let changes: Vec<&Action> = group.actions.iter().filter(|a| !matches!(a, Action::Comment(_))).collect();
+        assert_eq!(changes, vec![
+            &Action::remove(second.clone(), Some(first.display().to_string())),
+            &Action::remove(first.clone(), None),
+            &Action::Rename { from: newest.clone(), to: dir_path.join("doc.pdf") },
+        ]);
+
+        let group = Deduplicator::builder()
+            .filetype(".pdf")
+            .keep(KeepPolicy::Oldest)
+            .build()
+            .unwrap()
+            .plan_orphans(&dir_path.join("doc.pdf"), ".pdf", vec![newest.clone(), second.clone(), first.clone()])
+            .unwrap();
+        assert_eq!(group.actions.last(), Some(&Action::Rename { from: first, to: dir_path.join("doc.pdf") }));
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        write(&dir_path.join("Report.PDF"), "same");
+        write(&dir_path.join("report (1).Pdf"), "same");
+        write(&dir_path.join("notes.pdf"), "a");
+        write(&dir_path.join("Notes.pdf"), "b");
+        write(&dir_path.join("notes (1).pdf"), "a");
+
+        let builder = || Deduplicator::builder().root(dir_path).filetype(".pdf");
+        let report = builder().build().unwrap().run().unwrap();
+        assert_eq!(report.files.len(), 3);
+        assert_eq!(report.groups.len(), 1);
+        assert!(report.warnings.is_empty());
+
+        let report = builder().ignore_case(true).build().unwrap().run().unwrap();
+        assert_eq!(report.files.len(), 5);
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].base, dir_path.join("Report.PDF"));
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("Notes.pdf\" and \""));
+    }
+
+    #[test]
+    fn test_base_stem_strips_nested_copy_suffixes() {
+        let suffix = copy_suffix_regex(&[DEFAULT_COPY_PATTERN.to_string()], false).unwrap();
+        assert_eq!(base_stem("doc (1)", &suffix), Some("doc"));
+        assert_eq!(base_stem("doc (1) (1)", &suffix), Some("doc"));
+        assert_eq!(base_stem("doc (1)(2)", &suffix), Some("doc"));
+        assert_eq!(base_stem("doc(1) (2)", &suffix), Some("doc(1)"));
+        assert_eq!(base_stem("doc(1)(2)", &suffix), None);
+        assert_eq!(base_stem("doc", &suffix), None);
+        assert_eq!(base_stem(" (1)", &suffix), None);
+
+        let custom = copy_suffix_regex(&[r"_copy\d+".to_string()], false).unwrap();
+        assert_eq!(base_stem("doc_copy2_copy13", &custom), Some("doc"));
+    }
+
+    #[test]
+    fn test_nested_copies_form_one_group() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        for name in ["doc.pdf", "doc (1).pdf", "doc (1) (1).pdf", "doc (1)(2).pdf"] {
+            write(&dir_path.join(name), "same");
+        }
+
+        let report = Deduplicator::builder().root(dir_path).filetype(".pdf").build().unwrap().run().unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].base, dir_path.join("doc.pdf"));
+        assert_eq!(report.groups[0].members.len(), 4);
+        assert_eq!(report.actions().filter(|a| matches!(a, Action::Remove { .. })).count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlinks_are_already_linked() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let base = dir_path.join("doc.pdf");
+        let linked = dir_path.join("doc (1).pdf");
+        let copy = dir_path.join("doc (2).pdf");
+        let copy_link = dir_path.join("other.pdf");
+        write(&base, "same");
+        fs::hard_link(&base, &linked).unwrap();
+        write(&copy, "same");
+        fs::hard_link(&copy, &copy_link).unwrap();
+
+        let report = Deduplicator::builder().root(dir_path).filetype(".pdf").build().unwrap().run().unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.linked().collect::<Vec<_>>(), vec![&linked]);
+        let removed: Vec<&Action> = report.actions().filter(|a| matches!(a, Action::Remove { .. })).collect();
+        assert_eq!(removed, vec![&Action::remove(copy.clone(), Some(base.display().to_string()))]);
+        // "doc (2).pdf" keeps its data alive through "other.pdf"
+        assert_eq!(report.reclaimable(), 0);
+
+        fs::remove_file(&copy_link).unwrap();
+        assert_eq!(report.reclaimable(), 4);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_files_and_symlinks_are_skipped() {
+        use std::os::unix::{ffi::OsStrExt, fs::symlink};
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let outside = TempDir::new().unwrap();
+        let base = dir_path.join("doc.pdf");
+        let fifo = dir_path.join("doc (1).pdf");
+        let link = dir_path.join("doc (2).pdf");
+        let outside_link = dir_path.join("doc (3).pdf");
+        write(&base, "same");
+        let fifo_path = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo_path.as_ptr(), 0o644) }, 0);
+        symlink(&base, &link).unwrap();
+        write(&outside.path().join("doc.pdf"), "same");
+        symlink(outside.path().join("doc.pdf"), &outside_link).unwrap();
+        symlink(dir_path.join("b.pdf"), dir_path.join("a.pdf")).unwrap();
+        symlink(dir_path.join("a.pdf"), dir_path.join("b.pdf")).unwrap();
+
+        let builder = || Deduplicator::builder().root(dir_path).filetype(".pdf");
+        let report = builder().build().unwrap().run().unwrap();
+        assert_eq!(report.files, vec![base.clone()]);
+        assert!(report.groups.is_empty());
+        assert_eq!(report.warnings.len(), 5);
+        assert!(report.warnings[0].contains("not a regular file"));
+
+        let report = builder().follow_symlinks(true).build().unwrap().run().unwrap();
+        assert_eq!(report.files, vec![outside_link.clone(), base.clone()]);
+        assert_eq!(report.warnings.iter().filter(|w| w.contains("is a symlink loop")).count(), 2);
+        assert!(report.warnings.iter().any(|w| w.contains("which is scanned already")));
+        let removed: Vec<&Action> = report.actions().filter(|a| matches!(a, Action::Remove { .. })).collect();
+        assert_eq!(removed, vec![&Action::remove(outside_link.clone(), Some(base.display().to_string()))]);
+    }
+
+    #[test]
+    fn test_keep_policies() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let base = dir_path.join("doc.pdf");
+        let older = dir_path.join("doc (1).pdf");
+        let newer = dir_path.join("doc (2).pdf");
+        write(&base, "version a");
+        write(&older, "version bb");
+        write(&newer, "version ccc");
+        let files = vec![base.clone(), older.clone(), newer.clone()];
+
+        let actions = |keep: KeepPolicy| {
+            Deduplicator::builder()
+                .filetype(".pdf")
+                .keep(keep)
+                .build()
+                .unwrap()
+                .plan_group(&base, ".pdf", &files)
+                .unwrap()
+                .unwrap()
+                .actions
+                .into_iter()
+                .filter(|a| !matches!(a, Action::Comment(_)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(actions(KeepPolicy::Newest), vec![
+            Action::remove(base.clone(), None),
+            Action::remove(older.clone(), None),
+            Action::Rename { from: newer.clone(), to: base.clone() },
+        ]);
+        assert_eq!(actions(KeepPolicy::Oldest), vec![
+            Action::remove(newer.clone(), None),
+            Action::remove(older.clone(), None),
+        ]);
+        assert_eq!(actions(KeepPolicy::Base), vec![
+            Action::remove(newer.clone(), None),
+            Action::remove(older.clone(), None),
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_file_is_collected_and_run_continues() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        write(&dir_path.join("doc.pdf"), "doc");
+        std::os::unix::fs::symlink(dir_path.join("missing"), dir_path.join("doc (1).pdf")).unwrap();
+        write(&dir_path.join("other.pdf"), "same");
+        write(&dir_path.join("other (1).pdf"), "same");
+
+        // A dangling symlink can only be examined once it is followed
+        let builder = || Deduplicator::builder().root(dir_path).filetype(".pdf").follow_symlinks(true);
+        let report = builder().build().unwrap().run().unwrap();
+
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].base, dir_path.join("other.pdf"));
+        assert_eq!(report.errors.len(), 1);
+        assert!(matches!(report.errors[0], DedupError::Hash { .. }));
+        assert_eq!(report.errors[0].path(), dir_path.join("doc (1).pdf"));
+
+        assert!(builder().fail_fast(true).build().unwrap().run().is_err());
+    }
+
+    #[test]
+    fn test_hash_algorithm_changes_digests() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let base = dir_path.join("doc.pdf");
+        let copy = dir_path.join("doc (1).pdf");
+        write(&base, "abc");
+        write(&copy, "abc");
+
+        let group = Deduplicator::builder()
+            .filetype(".pdf")
+            .hash_algorithm(HashAlgorithm::Sha256)
+            .build()
+            .unwrap()
+            .plan_group(&base, ".pdf", &[base.clone(), copy.clone()])
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(group.members[0].digest, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_custom_hasher_is_used() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let base = dir_path.join("doc.pdf");
+        let copy = dir_path.join("doc (1).pdf");
+        write(&base, "one");
+        write(&copy, "two");
+
+        // A hasher that considers every file identical
+        let group = Deduplicator::builder()
+            .filetype(".pdf")
+            .hasher(Arc::new(|_: &Path| -> std::io::Result<String> { Ok("same".to_string()) }))
+            .build()
+            .unwrap()
+            .plan_group(&base, ".pdf", &[base.clone(), copy.clone()])
+            .unwrap()
+            .unwrap();
+
+        assert!(group.members.iter().all(|m| m.digest == "same"));
+        assert!(!group.actions.iter().any(|a| matches!(a, Action::Rename { .. })));
+    }
+
+    #[test]
+    fn test_run_hashes_only_compared_files_once() {
+        use std::sync::Mutex;
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        for name in ["doc.pdf", "doc (1).pdf", "doc (2).pdf", "other.pdf", "lonely (1).pdf"] {
+            write(&dir_path.join(name), name);
+        }
+
+        let hashed = Arc::new(Mutex::new(vec![]));
+        let seen = hashed.clone();
+        let report = Deduplicator::builder()
+            .root(dir_path)
+            .filetype(".pdf")
+            .io_threads(2)
+            .hash_threads(2)
+            .hasher(Arc::new(move |p: &Path| -> std::io::Result<String> {
+                seen.lock().unwrap().push(p.file_name().unwrap().to_string_lossy().into_owned());
+                Ok("same".to_string())
+            }))
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(report.groups.len(), 1);
+        let mut hashed = hashed.lock().unwrap().clone();
+        hashed.sort();
+        assert_eq!(hashed, vec!["doc (1).pdf", "doc (2).pdf", "doc.pdf"]);
+    }
+
+    #[test]
+    fn test_bandwidth_limit_is_per_instance() {
+        use std::time::{Duration, Instant};
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        for name in ["doc.pdf", "doc (1).pdf"] {
+            fs::write(dir_path.join(name), vec![7; 256 << 10]).unwrap();
+        }
+        let builder = || Deduplicator::builder().root(dir_path).filetype(".pdf");
+
+        let start = Instant::now();
+        let report = builder().bandwidth_limit(Bandwidth(1 << 20)).build().unwrap().run().unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        let start = Instant::now();
+        builder().build().unwrap().run().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_file_changed_during_scan_is_reported_once_hashed() {
+        use crate::error::ChangedDuringScan;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        write(&dir_path.join("doc.pdf"), "same");
+        write(&dir_path.join("doc (1).pdf"), "same");
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let report = Deduplicator::builder()
+            .root(dir_path)
+            .filetype(".pdf")
+            .hasher(Arc::new(move |p: &Path| -> io::Result<String> {
+                counted.fetch_add(1, Ordering::Relaxed);
+                if p.ends_with("doc (1).pdf") { Err(ChangedDuringScan::error()) } else { Ok("same".to_string()) }
+            }))
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert!(report.groups.is_empty());
+        assert_eq!(report.errors.len(), 1);
+        assert!(matches!(report.errors[0], DedupError::Changed { .. }));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+// A failure tied to one file. The run records these and carries on with the
+// other groups instead of aborting.
+#[derive(Debug)]
+pub enum DedupError {
+    Io { path: PathBuf, source: io::Error },
+    Permission { path: PathBuf, source: io::Error },
+    InvalidName { path: PathBuf },
+    Hash { path: PathBuf, source: io::Error },
+    Timestamp { path: PathBuf, source: io::Error },
+    // The file's size or mtime moved while it was being hashed
+    Changed { path: PathBuf },
+}
+
+// The cause of an io::Error raised when a file changes while it is read, e.g.
+// a download still being written. Its digest would match no version of it.
+#[derive(Debug)]
+pub(crate) struct ChangedDuringScan;
+
+impl fmt::Display for ChangedDuringScan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "changed during scan")
+    }
+}
+
+impl Error for ChangedDuringScan {}
+
+impl ChangedDuringScan {
+    pub(crate) fn error() -> io::Error {
+        io::Error::other(ChangedDuringScan)
+    }
+}
+
+// A copy of `e` for each name of a file hashed once: the same OS error or
+// kind and message, and still a ChangedDuringScan if it was one.
+pub(crate) fn copy_io_error(e: &io::Error) -> io::Error {
+    if let Some(code) = e.raw_os_error() {
+        io::Error::from_raw_os_error(code)
+    } else if e.get_ref().is_some_and(|e| e.is::<ChangedDuringScan>()) {
+        ChangedDuringScan::error()
+    } else {
+        io::Error::new(e.kind(), e.to_string())
+    }
+}
+
+impl DedupError {
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        Self::classify(path, source, |path, source| DedupError::Io { path, source })
+    }
+
+    pub fn hash(path: &Path, source: io::Error) -> Self {
+        Self::classify(path, source, |path, source| DedupError::Hash { path, source })
+    }
+
+    pub fn timestamp(path: &Path, source: io::Error) -> Self {
+        Self::classify(path, source, |path, source| DedupError::Timestamp { path, source })
+    }
+
+    pub fn invalid_name(path: &Path) -> Self {
+        DedupError::InvalidName { path: path.to_path_buf() }
+    }
+
+    // Permission problems and files that changed while being read are
+    // reported as such whatever the operation was.
+    fn classify<F>(path: &Path, source: io::Error, otherwise: F) -> Self
+    where
+        F: FnOnce(PathBuf, io::Error) -> Self,
+    {
+        let path = path.to_path_buf();
+        if source.kind() == io::ErrorKind::PermissionDenied {
+            DedupError::Permission { path, source }
+        } else if source.get_ref().is_some_and(|e| e.is::<ChangedDuringScan>()) {
+            DedupError::Changed { path }
+        } else {
+            otherwise(path, source)
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            DedupError::Io { path, .. }
+            | DedupError::Permission { path, .. }
+            | DedupError::InvalidName { path }
+            | DedupError::Hash { path, .. }
+            | DedupError::Timestamp { path, .. }
+            | DedupError::Changed { path } => path,
+        }
+    }
+}
+
+impl fmt::Display for DedupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DedupError::Io { path, source } => write!(f, "I/O error on {}: {}", path.display(), source),
+            DedupError::Permission { path, source } => write!(f, "Permission denied for {}: {}", path.display(), source),
+            DedupError::InvalidName { path } => write!(f, "Invalid file name: {}", path.display()),
+            DedupError::Hash { path, source } => write!(f, "Failed to hash {}: {}", path.display(), source),
+            DedupError::Timestamp { path, source } => write!(f, "Failed to get creation time for {}: {}", path.display(), source),
+            DedupError::Changed { path } => write!(f, "{} changed during scan; it is probably still being written", path.display()),
+        }
+    }
+}
+
+impl Error for DedupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DedupError::Io { source, .. }
+            | DedupError::Permission { source, .. }
+            | DedupError::Hash { source, .. }
+            | DedupError::Timestamp { source, .. } => Some(source),
+            DedupError::InvalidName { .. } | DedupError::Changed { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permission_denied_is_classified() {
+        let e = DedupError::hash(Path::new("a.pdf"), io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(matches!(e, DedupError::Permission { .. }));
+
+        let e = DedupError::hash(Path::new("a.pdf"), io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(e, DedupError::Hash { .. }));
+        assert_eq!(e.path(), Path::new("a.pdf"));
+        assert!(e.to_string().starts_with("Failed to hash a.pdf"));
+
+        let e = DedupError::hash(Path::new("a.pdf"), ChangedDuringScan::error());
+        assert!(matches!(e, DedupError::Changed { .. }));
+        assert!(e.to_string().contains("changed during scan"));
+    }
+}
@@ -2,48 +2,234 @@ use std::{
     fs::File,
     io::{self, BufReader, Read},
     path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
 };
-use blake3;
 
-// Optimized file hashing function
-pub fn file_hash(file_path: &Path) -> Result<String, io::Error> {
-    const SMALL_FILE_THRESHOLD: u64 = 1_000_000; // 1MB threshold
-    const BUFFER_SIZE: usize = 64 * 1024; // 64KB buffer for small files
-
-    // Open the file once
-    let file = File::open(file_path)?;
-    let metadata = file.metadata()?;
-    let file_size = metadata.len();
-    
-    if file_size < SMALL_FILE_THRESHOLD {
-        // For small files, use buffered reading with a single hasher
-        let mut hasher = blake3::Hasher::new();
-        let mut reader = BufReader::with_capacity(BUFFER_SIZE, file);
-        let mut buffer = [0; BUFFER_SIZE];
-        
-        loop {
-            let bytes_read = reader.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
-            }
-            hasher.update(&buffer[..bytes_read]);
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::error::ChangedDuringScan;
+use crate::file_util::open_noatime;
+use crate::throttle::{finished_reading, throttle};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// Produces the hex digest used to decide whether two files are identical.
+pub trait ContentHasher: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn hash_file(&self, path: &Path) -> io::Result<String>;
+
+    // A digester to feed the bytes of `path` to, so reading and hashing can
+    // happen on different threads. Hashers that answer without reading the
+    // file, or read it themselves, return None and get `hash_file` instead.
+    fn digester(&self, _path: &Path) -> Option<Box<dyn Digester>> {
+        None
+    }
+
+    // Called with the digest of `path` computed from one of our digesters.
+    fn record(&self, _path: &Path, _digest: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Digest state fed a file's bytes in order.
+pub trait Digester: Send {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl Digester for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+impl Digester for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", Digest::finalize(*self))
+    }
+}
+
+impl Digester for Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        Xxh3::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:032x}", self.digest128())
+    }
+}
+
+// Feed the whole of `path` to `digester`.
+fn digest_file(path: &Path, mut digester: Box<dyn Digester>) -> io::Result<String> {
+    read_chunks(path, |chunk| digester.update(chunk))?;
+    Ok(digester.finish())
+}
+
+// Any suitable closure can be used as a hasher, e.g. in tests.
+impl<F> ContentHasher for F
+where
+    F: Fn(&Path) -> io::Result<String> + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    fn hash_file(&self, path: &Path) -> io::Result<String> {
+        self(path)
+    }
+}
+
+pub struct Blake3Hasher;
+
+impl ContentHasher for Blake3Hasher {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn hash_file(&self, path: &Path) -> io::Result<String> {
+        file_hash(path)
+    }
+
+    fn digester(&self, _path: &Path) -> Option<Box<dyn Digester>> {
+        Some(Box::new(blake3::Hasher::new()))
+    }
+}
+
+// Matches the output of `sha256sum`, so digests can be checked against
+// vendor-published checksums.
+pub struct Sha256Hasher;
+
+impl ContentHasher for Sha256Hasher {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hash_file(&self, path: &Path) -> io::Result<String> {
+        digest_file(path, Box::new(Sha256::new()))
+    }
+
+    fn digester(&self, _path: &Path) -> Option<Box<dyn Digester>> {
+        Some(Box::new(Sha256::new()))
+    }
+}
+
+// 128-bit XXH3: not cryptographic, but fast enough for a quick pre-screen.
+pub struct Xxh3Hasher;
+
+impl ContentHasher for Xxh3Hasher {
+    fn name(&self) -> &'static str {
+        "xxh3"
+    }
+
+    fn hash_file(&self, path: &Path) -> io::Result<String> {
+        digest_file(path, Box::new(Xxh3::new()))
+    }
+
+    fn digester(&self, _path: &Path) -> Option<Box<dyn Digester>> {
+        Some(Box::new(Xxh3::new()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> Arc<dyn ContentHasher> {
+        match self {
+            HashAlgorithm::Blake3 => Arc::new(Blake3Hasher),
+            HashAlgorithm::Sha256 => Arc::new(Sha256Hasher),
+            HashAlgorithm::Xxh3 => Arc::new(Xxh3Hasher),
         }
-        
-        let hash = hasher.finalize();
-        Ok(hash.to_hex().to_string())
-    } else {
-        // For large files, use memory mapping which is faster for large files
-        let mmap = unsafe { memmap2::Mmap::map(&file)? };
-        
-        // Use rayon to parallelize the hashing of large files
-        let hash = blake3::Hasher::new()
-            .update_rayon(&mmap)
-            .finalize();
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            _ => Err(format!("Unknown hash algorithm '{}' (expected blake3, sha256 or xxh3)", s)),
+        }
+    }
+}
+
+// The size and mtime of an open file, taken before it is read and compared
+// after, so a file that changes underneath is not given a digest.
+pub(crate) struct Snapshot {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Snapshot {
+    pub(crate) fn of(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Snapshot { len: metadata.len(), modified: metadata.modified().ok() })
+    }
 
-        Ok(hash.to_hex().to_string())
+    // Fails with ChangedDuringScan unless exactly the file's length was read
+    // and its size and mtime are still the same.
+    pub(crate) fn verify(&self, file: &File, bytes_read: u64) -> io::Result<()> {
+        let now = Snapshot::of(file)?;
+        if bytes_read == self.len && now.len == self.len && now.modified == self.modified {
+            Ok(())
+        } else {
+            Err(ChangedDuringScan::error())
+        }
     }
 }
 
+// Feed the bytes of `path` to `update` a buffer at a time, within the
+// bandwidth limit.
+fn read_chunks<F>(path: &Path, mut update: F) -> io::Result<()>
+where
+    F: FnMut(&[u8]),
+{
+    let file = open_noatime(path)?;
+    let snapshot = Snapshot::of(&file)?;
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, &file);
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        throttle(bytes_read);
+        update(&buffer[..bytes_read]);
+        total += bytes_read as u64;
+    }
+    finished_reading(&file);
+    snapshot.verify(&file, total)
+}
+
+// BLAKE3 digest of the file at `file_path`. Files are read, never mapped: a
+// mapped file truncated by another process mid-hash kills the process with
+// SIGBUS, where a read just comes up short.
+pub fn file_hash(file_path: &Path) -> Result<String, io::Error> {
+    digest_file(file_path, Box::new(blake3::Hasher::new()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,4 +253,54 @@ mod tests {
         let expected_hash = blake3::hash("Hello, World!\n".as_bytes());
         assert_eq!(hash, expected_hash.to_hex().to_string());
     }
+
+    #[test]
+    fn test_file_changed_while_read_is_an_error() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&vec![7; 3 * BUFFER_SIZE]).unwrap();
+        let path = file.path().to_path_buf();
+
+        // Grows after the first buffer is read
+        let mut first = true;
+        let grown = read_chunks(&path, |_| {
+            if std::mem::take(&mut first) {
+                file.write_all(b"more").unwrap();
+            }
+        });
+        assert!(grown.unwrap_err().get_ref().unwrap().is::<ChangedDuringScan>());
+
+        // Shrinks after the first buffer is read
+        let mut first = true;
+        let truncated = read_chunks(&path, |_| {
+            if std::mem::take(&mut first) {
+                file.as_file().set_len(10).unwrap();
+            }
+        });
+        assert!(truncated.unwrap_err().get_ref().unwrap().is::<ChangedDuringScan>());
+
+        assert!(read_chunks(&path, |_| {}).is_ok());
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"abc").unwrap();
+        let file_path = file.path();
+
+        let sha256 = HashAlgorithm::Sha256.hasher().hash_file(file_path).unwrap();
+        assert_eq!(sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let xxh3 = "xxh3".parse::<HashAlgorithm>().unwrap().hasher();
+        assert_eq!(xxh3.name(), "xxh3");
+        assert_eq!(
+            xxh3.hash_file(file_path).unwrap(),
+            format!("{:032x}", xxhash_rust::xxh3::xxh3_128(b"abc"))
+        );
+
+        assert_eq!(
+            HashAlgorithm::default().hasher().hash_file(file_path).unwrap(),
+            file_hash(file_path).unwrap()
+        );
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
 }
@@ -1,16 +1,342 @@
 use std::{
-    fs,
-    io,
+    fs::{self, File},
+    io::{self, BufReader, Read},
     path::Path,
-    time::SystemTime,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use crate::throttle::PacedFile;
+
 pub fn get_creation_time(file_path: &Path) -> io::Result<SystemTime> {
     let metadata = fs::metadata(file_path)?;
     let creation_time = metadata.created()?;
     Ok(creation_time)
 }
 
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+// Filesystems without the flag-taking rename report one of these.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn unsupported(e: io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::ENOTSUP) => {
+            io::Error::new(io::ErrorKind::Unsupported, e)
+        }
+        _ => e,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn rename_with_flags(from: &Path, to: &Path, flags: libc::c_uint) -> io::Result<()> {
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    // SAFETY: both paths are NUL-terminated and outlive the call
+    let rc = unsafe { libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), flags) };
+    if rc == 0 { Ok(()) } else { Err(unsupported(io::Error::last_os_error())) }
+}
+
+#[cfg(target_os = "macos")]
+fn rename_with_flags(from: &Path, to: &Path, flags: libc::c_uint) -> io::Result<()> {
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    // SAFETY: both paths are NUL-terminated and outlive the call
+    let rc = unsafe { libc::renamex_np(from.as_ptr(), to.as_ptr(), flags) };
+    if rc == 0 { Ok(()) } else { Err(unsupported(io::Error::last_os_error())) }
+}
+
+// Rename `from` to `to`, failing if `to` exists instead of replacing it.
+pub fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    let result = rename_with_flags(from, to, libc::RENAME_NOREPLACE);
+    #[cfg(target_os = "macos")]
+    let result = rename_with_flags(from, to, libc::RENAME_EXCL);
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    let result: io::Result<()> = Err(io::ErrorKind::Unsupported.into());
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            // Best effort where the kernel or filesystem cannot do it atomically
+            if to.symlink_metadata().is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists", to.display())));
+            }
+            fs::rename(from, to)
+        }
+        result => result,
+    }
+}
+
+// Atomically swap the files at `a` and `b`. Fails with `Unsupported` where
+// the platform or filesystem cannot.
+pub fn rename_exchange(a: &Path, b: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return rename_with_flags(a, b, libc::RENAME_EXCHANGE);
+    #[cfg(target_os = "macos")]
+    return rename_with_flags(a, b, libc::RENAME_SWAP);
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = (a, b);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+// Modification time in nanoseconds since the epoch, as recorded in plan files.
+pub fn modified_ns(metadata: &fs::Metadata) -> io::Result<i64> {
+    let since_epoch = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?;
+    i64::try_from(since_epoch.as_nanos()).map_err(io::Error::other)
+}
+
+// The (device, inode) pair behind a name: hardlinked names share it. None
+// where the platform has no such notion.
+#[cfg(unix)]
+pub fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Whether resolving a path failed because its symlinks form a loop.
+#[cfg(unix)]
+pub fn is_symlink_loop(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ELOOP)
+}
+
+#[cfg(not(unix))]
+pub fn is_symlink_loop(_e: &io::Error) -> bool {
+    false
+}
+
+// Whether the block device `dev` is a spinning disk, where reads should stay
+// sequential. A partition answers for the disk it is on.
+#[cfg(target_os = "linux")]
+pub fn is_rotational(dev: u64) -> bool {
+    let block = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+    [format!("{block}/queue/rotational"), format!("{block}/../queue/rotational")]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .is_some_and(|flag| flag.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_rotational(_dev: u64) -> bool {
+    false
+}
+
+// How many names the file has.
+#[cfg(unix)]
+pub fn link_count(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+pub fn link_count(_metadata: &fs::Metadata) -> u64 {
+    1
+}
+
+// Permission bits, including setuid, setgid and sticky.
+#[cfg(unix)]
+pub fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+pub fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+pub fn set_modified_ns(path: &Path, mtime_ns: i64) -> io::Result<()> {
+    let since_epoch = Duration::from_nanos(u64::try_from(mtime_ns).map_err(io::Error::other)?);
+    File::open(path)?.set_modified(UNIX_EPOCH + since_epoch)
+}
+
+// Namespaces whose attributes an ordinary user can copy between files.
+#[cfg(target_os = "linux")]
+fn copyable_xattr(name: &[u8]) -> bool {
+    name.starts_with(b"user.")
+}
+
+#[cfg(target_os = "macos")]
+fn copyable_xattr(_name: &[u8]) -> bool {
+    true
+}
+
+// Fill a buffer from a call that reports the size it needs when given none.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_sized<F>(call: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    let size = call(std::ptr::null_mut(), 0);
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; size as usize];
+    let size = call(buf.as_mut_ptr().cast(), buf.len());
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(size as usize);
+    Ok(buf)
+}
+
+// The extended attributes of `path` that `set_xattr` can give another file.
+// A filesystem without extended attributes has none.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn xattrs(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let c = c_path(path)?;
+    // SAFETY: the path is NUL-terminated and the buffer holds `size` bytes
+    #[cfg(target_os = "linux")]
+    let names = read_sized(|buf, size| unsafe { libc::listxattr(c.as_ptr(), buf.cast(), size) });
+    #[cfg(target_os = "macos")]
+    let names = read_sized(|buf, size| unsafe { libc::listxattr(c.as_ptr(), buf.cast(), size, 0) });
+    let names = match names {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(vec![]),
+        names => names?,
+    };
+
+    let mut attributes = vec![];
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty() && copyable_xattr(n)) {
+        let c_name = std::ffi::CString::new(name).map_err(io::Error::other)?;
+        // SAFETY: both strings are NUL-terminated and the buffer holds `size` bytes
+        #[cfg(target_os = "linux")]
+        let value = read_sized(|buf, size| unsafe { libc::getxattr(c.as_ptr(), c_name.as_ptr(), buf, size) })?;
+        #[cfg(target_os = "macos")]
+        let value = read_sized(|buf, size| unsafe { libc::getxattr(c.as_ptr(), c_name.as_ptr(), buf, size, 0, 0) })?;
+        attributes.push((String::from_utf8_lossy(name).into_owned(), value));
+    }
+    Ok(attributes)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn xattrs(_path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(vec![])
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let c = c_path(path)?;
+    let c_name = std::ffi::CString::new(name).map_err(io::Error::other)?;
+    // SAFETY: both strings are NUL-terminated and `value` outlives the call
+    #[cfg(target_os = "linux")]
+    let rc = unsafe { libc::setxattr(c.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    #[cfg(target_os = "macos")]
+    let rc = unsafe { libc::setxattr(c.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0, 0) };
+    if rc == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Open `path` for reading without updating its access time where the kernel
+// allows it. Only the owner may ask for that, so anyone else gets a plain open.
+#[cfg(target_os = "linux")]
+pub fn open_noatime(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match fs::OpenOptions::new().read(true).custom_flags(libc::O_NOATIME).open(path) {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => File::open(path),
+        result => result,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_noatime(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+// Tell the kernel the cached pages of `file` are no longer needed.
+#[cfg(target_os = "linux")]
+pub fn drop_page_cache(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor stays open for the duration of the call. This is
+    // advice only, so a failure changes nothing.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_page_cache(_file: &File) {}
+
+// Lower the CPU and I/O priority of the calling thread, and so of the threads
+// it starts afterwards, like `nice -n 19 ionice -c 3`.
+#[cfg(target_os = "linux")]
+pub fn lower_priority() -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    // SAFETY: plain system calls on the calling thread
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let idle = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, idle) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn lower_priority() -> io::Result<()> {
+    // SAFETY: a plain system call on the calling process
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn lower_priority() -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// True if `short` is shorter than `long` and every byte of `short` matches the
+// start of `long`: the signature of an interrupted download.
+pub fn is_strict_prefix(short: &Path, long: &Path) -> io::Result<bool> {
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    let short_len = fs::metadata(short)?.len();
+    let long_len = fs::metadata(long)?.len();
+    if short_len >= long_len {
+        return Ok(false);
+    }
+
+    let mut short_reader = BufReader::with_capacity(BUFFER_SIZE, PacedFile::open(short)?);
+    let mut long_reader = BufReader::with_capacity(BUFFER_SIZE, PacedFile::open(long)?).take(short_len);
+    let mut short_buf = vec![0; BUFFER_SIZE];
+    let mut long_buf = vec![0; BUFFER_SIZE];
+    loop {
+        let n = short_reader.read(&mut short_buf)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        long_reader.read_exact(&mut long_buf[..n])?;
+        if short_buf[..n] != long_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -21,7 +347,7 @@ mod tests {
     use chrono::prelude::{DateTime, Utc, Local};
 
     fn iso8601(st: &std::time::SystemTime) -> String {
-        let utc: DateTime<Utc> = st.clone().into();
+        let utc: DateTime<Utc> = (*st).into();
         let local: DateTime<Local> = DateTime::from(utc);
         format!("{}", local.format("%+"))
         // formats like "2001-07-08T00:34:60.026490+09:30"
@@ -46,4 +372,19 @@ mod tests {
         let s = t.to_rfc3339();
         assert!(today.starts_with(&s[0..10]));
     }
+
+    #[test]
+    fn test_is_strict_prefix() {
+        let mut full = NamedTempFile::new().unwrap();
+        full.write_all(b"complete download").unwrap();
+        let mut partial = NamedTempFile::new().unwrap();
+        partial.write_all(b"complete").unwrap();
+        let mut other = NamedTempFile::new().unwrap();
+        other.write_all(b"elsewhere").unwrap();
+
+        assert!(is_strict_prefix(partial.path(), full.path()).unwrap());
+        assert!(!is_strict_prefix(full.path(), partial.path()).unwrap());
+        assert!(!is_strict_prefix(full.path(), full.path()).unwrap());
+        assert!(!is_strict_prefix(other.path(), full.path()).unwrap());
+    }
 }
@@ -0,0 +1,271 @@
+use std::{
+    collections::HashSet,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use image::{imageops::FilterType, ImageReader};
+use rayon::prelude::*;
+
+use crate::dedup::{DuplicateGroup, GroupMember};
+use crate::file_hash::ContentHasher;
+use crate::throttle::{self, PacedFile, Throttle};
+use crate::{Action, MyResult};
+
+// dHash works on a 9x8 grayscale thumbnail: 8 comparisons per row, 8 rows.
+const DHASH_WIDTH: u32 = 9;
+const DHASH_HEIGHT: u32 = 8;
+
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub dhash: u64,
+    // Digest of the file's bytes
+    pub digest: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct NearDuplicate {
+    pub image: ImageInfo,
+    pub distance: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct NearDuplicateGroup {
+    pub keeper: ImageInfo,
+    pub near_duplicates: Vec<NearDuplicate>,
+}
+
+// Difference hash: shrink to 9x8 grayscale and set one bit per pixel
+// that is brighter than its right-hand neighbour.
+pub fn dhash(path: &Path) -> MyResult<ImageInfo> {
+    let reader = ImageReader::new(BufReader::new(PacedFile::open(path)?)).with_guessed_format()?;
+    let img = reader.decode()
+        .map_err(|e| format!("Failed to decode image {}: {}", path.display(), e))?;
+    let thumb = img
+        .resize_exact(DHASH_WIDTH, DHASH_HEIGHT, FilterType::Triangle)
+        .to_luma8();
+
+    let mut hash: u64 = 0;
+    for y in 0..DHASH_HEIGHT {
+        for x in 0..DHASH_WIDTH - 1 {
+            let left = thumb.get_pixel(x, y)[0];
+            let right = thumb.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(left > right);
+        }
+    }
+
+    Ok(ImageInfo {
+        path: path.to_path_buf(),
+        width: img.width(),
+        height: img.height(),
+        dhash: hash,
+        digest: String::new(),
+    })
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+fn area(image: &ImageInfo) -> u64 {
+    u64::from(image.width) * u64::from(image.height)
+}
+
+// Group images whose dHashes are within `max_distance` bits of the image
+// kept for the group, the largest rendition. Every member is compared with
+// the keeper itself, so a chain of small differences never adds up to a
+// group. Byte-identical files are collapsed first: those are exact duplicates
+// and belong to the normal exact pass, not to this report. Files that cannot
+// be read or decoded are left out with a warning.
+pub fn near_duplicate_images(
+    files: &[PathBuf],
+    max_distance: u32,
+    hasher: &dyn ContentHasher,
+) -> (Vec<NearDuplicateGroup>, Vec<String>) {
+    // Pooled threads outlive the scan, so they are paced but not reprioritised
+    let pacing = throttle::current();
+    let hashed: Vec<MyResult<ImageInfo>> = files
+        .par_iter()
+        .map(|path| -> MyResult<ImageInfo> {
+            let _paced = pacing.as_ref().map(Throttle::enter);
+            let digest = hasher.hash_file(path)
+                .map_err(|e| format!("Failed to hash {}: {}", path.display(), e))?;
+            Ok(ImageInfo { digest, ..dhash(path)? })
+        })
+        .collect();
+
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut images: Vec<ImageInfo> = vec![];
+    let mut warnings = vec![];
+    for info in hashed {
+        match info {
+            Ok(info) if seen.insert(info.digest.clone()) => images.push(info),
+            Ok(_) => {}
+            Err(e) => warnings.push(format!("{}; it was left out of the near-duplicate images", e)),
+        }
+    }
+
+    (group_near_duplicates(images, max_distance), warnings)
+}
+
+// Each group's keeper is the largest image not grouped yet, and its members
+// the other ungrouped images within `max_distance` of it.
+fn group_near_duplicates(mut images: Vec<ImageInfo>, max_distance: u32) -> Vec<NearDuplicateGroup> {
+    // Largest first; break ties by path for stable output.
+    images.sort_by(|a, b| area(b).cmp(&area(a)).then_with(|| a.path.cmp(&b.path)));
+    let mut grouped = vec![false; images.len()];
+    let mut groups = vec![];
+    for i in 0..images.len() {
+        if grouped[i] {
+            continue;
+        }
+        let keeper = &images[i];
+        let mut near_duplicates = vec![];
+        for j in (i + 1)..images.len() {
+            let distance = hamming_distance(keeper.dhash, images[j].dhash);
+            if !grouped[j] && distance <= max_distance {
+                grouped[j] = true;
+                near_duplicates.push(NearDuplicate { image: images[j].clone(), distance });
+            }
+        }
+        if !near_duplicates.is_empty() {
+            groups.push(NearDuplicateGroup { keeper: keeper.clone(), near_duplicates });
+        }
+    }
+    groups.sort_by(|a, b| a.keeper.path.cmp(&b.keeper.path));
+    groups
+}
+
+// Near-duplicates are only reported as comments unless deletion was
+// explicitly requested: visually similar is not the same as identical. The
+// groups render like exact ones, so removals run behind the same guard.
+pub fn near_duplicate_groups(groups: &[NearDuplicateGroup], delete: bool) -> Vec<DuplicateGroup> {
+    groups
+        .iter()
+        .map(|group| {
+            let keeper = &group.keeper;
+            let mut actions = vec![Action::Comment(format!(
+                "{} near-duplicate images of {} ({}x{})",
+                "~".repeat(30), keeper.path.display(), keeper.width, keeper.height
+            ))];
+            for near in &group.near_duplicates {
+                let image = &near.image;
+                actions.push(Action::Comment(format!(
+                    "{} ({}x{}) distance {}",
+                    image.path.display(), image.width, image.height, near.distance
+                )));
+                if delete {
+                    let reason = format!("near-duplicate of {} (distance {})", keeper.path.display(), near.distance);
+                    actions.push(Action::remove(image.path.clone(), Some(reason)));
+                }
+            }
+            let members = std::iter::once(keeper)
+                .chain(group.near_duplicates.iter().map(|near| &near.image))
+                .map(|image| GroupMember { path: image.path.clone(), digest: image.digest.clone() })
+                .collect();
+            DuplicateGroup { base: keeper.path.clone(), members, actions, linked: vec![] }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_hash::HashAlgorithm;
+    use image::{ImageBuffer, Luma};
+    use tempfile::TempDir;
+
+    fn gradient(path: &Path, width: u32, height: u32, invert: bool) {
+        let img = ImageBuffer::from_fn(width, height, |x, _| {
+            let v = (x * 255 / width) as u8;
+            Luma([if invert { 255 - v } else { v }])
+        });
+        img.save(path).unwrap();
+    }
+
+    #[test]
+    fn test_hamming_distance() {
+        assert_eq!(hamming_distance(0, 0), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0001), 2);
+        assert_eq!(hamming_distance(u64::MAX, 0), 64);
+    }
+
+    #[test]
+    fn test_resized_copy_is_near_duplicate() {
+        let temp_dir = TempDir::new().unwrap();
+        let big = temp_dir.path().join("photo.png");
+        let small = temp_dir.path().join("photo-small.png");
+        let other = temp_dir.path().join("other.png");
+        gradient(&big, 200, 100, false);
+        gradient(&small, 100, 50, false);
+        gradient(&other, 200, 100, true);
+
+        let files = vec![big.clone(), small.clone(), other];
+        let groups = near_duplicate_images(&files, 5, HashAlgorithm::Blake3.hasher().as_ref()).0;
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].keeper.path, big);
+        assert_eq!(groups[0].near_duplicates.len(), 1);
+        assert_eq!(groups[0].near_duplicates[0].image.path, small);
+    }
+
+    #[test]
+    fn test_members_are_all_close_to_the_keeper() {
+        let image = |name: &str, width: u32, dhash: u64| ImageInfo {
+            path: PathBuf::from(name),
+            width,
+            height: 10,
+            dhash,
+            digest: name.to_string(),
+        };
+        // a and b differ by 2 bits, b and c by 2, a and c by 4
+        let images = vec![image("a", 30, 0b0000), image("b", 20, 0b0011), image("c", 10, 0b1111)];
+
+        let groups = group_near_duplicates(images, 2);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].keeper.path, PathBuf::from("a"));
+        let members: Vec<&Path> = groups[0].near_duplicates.iter().map(|n| n.image.path.as_path()).collect();
+        assert_eq!(members, vec![Path::new("b")]);
+    }
+
+    #[test]
+    fn test_undecodable_image_is_skipped_with_warning() {
+        let temp_dir = TempDir::new().unwrap();
+        let big = temp_dir.path().join("photo.png");
+        let small = temp_dir.path().join("photo-small.png");
+        let broken = temp_dir.path().join("broken.png");
+        gradient(&big, 200, 100, false);
+        gradient(&small, 100, 50, false);
+        std::fs::write(&broken, b"not an image").unwrap();
+
+        let (groups, warnings) = near_duplicate_images(&[big, broken, small], 5, HashAlgorithm::Blake3.hasher().as_ref());
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("broken.png"));
+    }
+
+    #[test]
+    fn test_render_only_deletes_when_asked() {
+        let temp_dir = TempDir::new().unwrap();
+        let big = temp_dir.path().join("photo.png");
+        let small = temp_dir.path().join("photo-small.png");
+        gradient(&big, 200, 100, false);
+        gradient(&small, 100, 50, false);
+
+        let groups = near_duplicate_images(&[big, small], 5, HashAlgorithm::Blake3.hasher().as_ref()).0;
+
+        let removals = |delete| {
+            near_duplicate_groups(&groups, delete)[0].actions
+                .iter()
+                .filter(|a| matches!(a, Action::Remove { .. }))
+                .count()
+        };
+        assert_eq!(removals(false), 0);
+        assert_eq!(removals(true), 1);
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::apply::trash_info_path;
+use crate::file_hash::file_hash;
+use crate::file_util::{rename_exchange, rename_noreplace};
+use crate::MyResult;
+
+const HEADER: &str = "# file-dup journal v1";
+
+// One change made by a native apply, with enough detail to reverse it. Sizes
+// and BLAKE3 digests are of the moved file, so undo only moves back the file
+// that was moved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JournalEntry {
+    // `path` was moved into quarantine at `quarantined`
+    Remove { path: PathBuf, quarantined: PathBuf, size: u64, digest: String },
+    // `path` was moved to the desktop trash at `trashed`
+    Trash { path: PathBuf, trashed: PathBuf, size: u64, digest: String },
+    Rename { from: PathBuf, to: PathBuf, size: u64, digest: String },
+    // `from` and `to` were swapped; beforehand they held `from_size` and
+    // `to_size` bytes with the matching digests
+    Exchange {
+        from: PathBuf,
+        to: PathBuf,
+        from_size: u64,
+        to_size: u64,
+        from_digest: String,
+        to_digest: String,
+    },
+}
+
+// The raw bytes of `path`, with tabs, newlines and backslashes escaped, and
+// bytes that are not UTF-8 written as \xHH.
+pub(crate) fn escape(path: &Path) -> String {
+    let mut result = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => result.push_str("\\\\"),
+                '\t' => result.push_str("\\t"),
+                '\n' => result.push_str("\\n"),
+                c => result.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            result.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    result
+}
+
+pub(crate) fn unescape(field: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest.split_first() {
+            Some((b'x', tail)) => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+                continue;
+            }
+            Some((b't', _)) => bytes.push(b'\t'),
+            Some((b'n', _)) => bytes.push(b'\n'),
+            Some((&other, _)) => bytes.push(other),
+            None => bytes.push(b'\\'),
+        }
+        rest = rest.get(1..).unwrap_or_default();
+    }
+    Some(path_from_bytes(bytes))
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn is_digest(field: &str) -> bool {
+    !field.is_empty() && field.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl JournalEntry {
+    fn to_line(&self) -> String {
+        match self {
+            JournalEntry::Remove { path, quarantined, size, digest } => {
+                format!("remove\t{}\t{}\t{}\t{}", escape(path), escape(quarantined), size, digest)
+            }
+            JournalEntry::Trash { path, trashed, size, digest } => {
+                format!("trash\t{}\t{}\t{}\t{}", escape(path), escape(trashed), size, digest)
+            }
+            JournalEntry::Rename { from, to, size, digest } => {
+                format!("rename\t{}\t{}\t{}\t{}", escape(from), escape(to), size, digest)
+            }
+            JournalEntry::Exchange { from, to, from_size, to_size, from_digest, to_digest } => {
+                format!(
+                    "exchange\t{}\t{}\t{}\t{}\t{}\t{}",
+                    escape(from), escape(to), from_size, to_size, from_digest, to_digest
+                )
+            }
+        }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if let ["exchange", from, to, from_size, to_size, from_digest, to_digest] = fields[..] {
+            if !is_digest(from_digest) || !is_digest(to_digest) {
+                return None;
+            }
+            return Some(JournalEntry::Exchange {
+                from: unescape(from)?,
+                to: unescape(to)?,
+                from_size: from_size.parse().ok()?,
+                to_size: to_size.parse().ok()?,
+                from_digest: from_digest.to_string(),
+                to_digest: to_digest.to_string(),
+            });
+        }
+        let [verb, a, b, size, digest] = fields[..] else {
+            return None;
+        };
+        let (a, b, size) = (unescape(a)?, unescape(b)?, size.parse().ok()?);
+        if !is_digest(digest) {
+            return None;
+        }
+        let digest = digest.to_string();
+        match verb {
+            "remove" => Some(JournalEntry::Remove { path: a, quarantined: b, size, digest }),
+            "trash" => Some(JournalEntry::Trash { path: a, trashed: b, size, digest }),
+            "rename" => Some(JournalEntry::Rename { from: a, to: b, size, digest }),
+            _ => None,
+        }
+    }
+}
+
+// Append-only record of applied actions. Undo cuts reversed entries off the
+// end again.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> MyResult<Self> {
+        let is_new = !path.exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Failed to open journal {}: {}", path.display(), e))?;
+        if is_new {
+            writeln!(file, "{}", HEADER)?;
+        }
+        Ok(Journal { path: path.to_path_buf(), file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Each entry is flushed to disk before the next action runs, so the
+    // journal survives a crash part-way through an apply.
+    pub fn record(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        writeln!(self.file, "{}", entry.to_line())?;
+        self.file.sync_data()
+    }
+}
+
+// Each entry with the byte offset its line starts at.
+fn read_entries(path: &Path) -> MyResult<Vec<(u64, JournalEntry)>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read journal {}: {}", path.display(), e))?;
+    let mut entries = vec![];
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let start = offset;
+        offset += line.len() as u64;
+        let line = line.trim_end_matches('\n');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = JournalEntry::parse(line)
+            .ok_or_else(|| format!("Malformed line {} in journal {}", i + 1, path.display()))?;
+        entries.push((start, entry));
+    }
+    Ok(entries)
+}
+
+pub fn read_journal(path: &Path) -> MyResult<Vec<JournalEntry>> {
+    Ok(read_entries(path)?.into_iter().map(|(_, entry)| entry).collect())
+}
+
+// The size and digest of each file undo has moved so far; None where it
+// moved one away.
+type State = HashMap<PathBuf, Option<(u64, String)>>;
+
+// Size of the file at `path` as undo will find it: earlier steps of the
+// same undo override what is on disk.
+fn size_at(path: &Path, state: &State) -> Option<u64> {
+    match state.get(path) {
+        Some(file) => file.as_ref().map(|(size, _)| *size),
+        None => fs::symlink_metadata(path).ok().map(|m| m.len()),
+    }
+}
+
+fn digest_at(path: &Path, state: &State) -> Option<String> {
+    match state.get(path) {
+        Some(file) => file.as_ref().map(|(_, digest)| digest.clone()),
+        None => file_hash(path).ok(),
+    }
+}
+
+fn describe(size: Option<u64>) -> String {
+    match size {
+        Some(size) => format!("a file of {} bytes", size),
+        None => "missing".to_string(),
+    }
+}
+
+// Fails unless `path` holds `expected`, a file's size and digest, or nothing
+// when that is None.
+fn expect(path: &Path, expected: Option<(u64, &str)>, state: &State) -> MyResult<()> {
+    let actual = size_at(path, state);
+    if actual != expected.map(|(size, _)| size) {
+        return Err(format!(
+            "Cannot undo: {} should be {} but is {}",
+            path.display(), describe(expected.map(|(size, _)| size)), describe(actual)
+        ).into());
+    }
+    if let Some((_, digest)) = expected
+        && digest_at(path, state).as_deref() != Some(digest)
+    {
+        return Err(format!("Cannot undo: {} is not the file that was moved there", path.display()).into());
+    }
+    Ok(())
+}
+
+// The (source, destination, size, digest) of the move that reverses `entry`;
+// none for an exchange, which is reversed by swapping again.
+fn reversal(entry: &JournalEntry) -> Option<(&Path, &Path, u64, &str)> {
+    match entry {
+        JournalEntry::Remove { path, quarantined, size, digest } => Some((quarantined, path, *size, digest)),
+        JournalEntry::Trash { path, trashed, size, digest } => Some((trashed, path, *size, digest)),
+        JournalEntry::Rename { from, to, size, digest } => Some((to, from, *size, digest)),
+        JournalEntry::Exchange { .. } => None,
+    }
+}
+
+// Reverse every entry of a journal, newest first. Nothing is touched unless
+// every affected path holds what the journal expects. Each entry is cut off
+// the journal once reversed, so an undo that fails part-way can be run again.
+pub fn undo_journal<F>(path: &Path, mut undone: F) -> MyResult<usize>
+where
+    F: FnMut(&JournalEntry),
+{
+    let entries = read_entries(path)?;
+
+    let mut state = State::new();
+    for (_, entry) in entries.iter().rev() {
+        if let JournalEntry::Exchange { from, to, from_size, to_size, from_digest, to_digest } = entry {
+            expect(from, Some((*to_size, to_digest)), &state)?;
+            expect(to, Some((*from_size, from_digest)), &state)?;
+            state.insert(from.clone(), Some((*from_size, from_digest.clone())));
+            state.insert(to.clone(), Some((*to_size, to_digest.clone())));
+            continue;
+        }
+        if let Some((source, destination, size, digest)) = reversal(entry) {
+            expect(source, Some((size, digest)), &state)?;
+            expect(destination, None, &state)?;
+            state.insert(source.to_path_buf(), None);
+            state.insert(destination.to_path_buf(), Some((size, digest.to_string())));
+        }
+    }
+
+    let journal = OpenOptions::new().write(true).open(path)
+        .map_err(|e| format!("Failed to open journal {}: {}", path.display(), e))?;
+    for (offset, entry) in entries.iter().rev() {
+        if let JournalEntry::Exchange { from, to, .. } = entry {
+            rename_exchange(from, to)
+                .map_err(|e| format!("Failed to swap {} back with {}: {}", from.display(), to.display(), e))?;
+        } else if let Some((source, destination, _, _)) = reversal(entry) {
+            rename_noreplace(source, destination)
+                .map_err(|e| format!("Failed to move {} back to {}: {}", source.display(), destination.display(), e))?;
+        }
+        if let JournalEntry::Trash { trashed, .. } = entry {
+            // The file is out of the trash, so its metadata entry is stale
+            let _ = fs::remove_file(trash_info_path(trashed));
+        }
+        journal.set_len(*offset)?;
+        journal.sync_data()?;
+        undone(entry);
+    }
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn digest(content: &[u8]) -> String {
+        blake3::hash(content).to_hex().to_string()
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let entries = [
+            JournalEntry::Remove {
+                path: PathBuf::from("/d/we\tird\\name.pdf"),
+                quarantined: PathBuf::from("/q/1-name.pdf"),
+                size: 12,
+                digest: digest(b"x"),
+            },
+            JournalEntry::Trash {
+                path: PathBuf::from("/d/b.pdf"),
+                trashed: PathBuf::from("/t/files/b.pdf"),
+                size: 4,
+                digest: digest(b"x"),
+            },
+            JournalEntry::Rename {
+                from: PathBuf::from("/d/a (1).pdf"),
+                to: PathBuf::from("/d/a.pdf"),
+                size: 3,
+                digest: digest(b"x"),
+            },
+            JournalEntry::Exchange {
+                from: PathBuf::from("/d/a (1).pdf"),
+                to: PathBuf::from("/d/a.pdf"),
+                from_size: 5,
+                to_size: 3,
+                from_digest: digest(b"a"),
+                to_digest: digest(b"b"),
+            },
+        ];
+        for entry in entries {
+            assert_eq!(JournalEntry::parse(&entry.to_line()), Some(entry));
+        }
+        assert_eq!(JournalEntry::parse("delete\t/a\t/b\t1\tab"), None);
+        assert_eq!(JournalEntry::parse("remove\t/a\t/b\t1"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = PathBuf::from(std::ffi::OsStr::from_bytes(b"/d/caf\xe9 \\x41.pdf"));
+        let entry = JournalEntry::Rename { from: path.clone(), to: PathBuf::from("/d/a.pdf"), size: 1, digest: digest(b"x") };
+        let line = entry.to_line();
+        assert!(line.starts_with("rename\t/d/caf\\xe9 \\\\x41.pdf\t"));
+        assert_eq!(JournalEntry::parse(&line), Some(entry));
+    }
+
+    #[test]
+    fn test_undo_refuses_changed_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let moved = temp_dir.path().join("a.pdf");
+        fs::write(&moved, b"changed after apply").unwrap();
+
+        let journal_path = temp_dir.path().join("journal.log");
+        let mut journal = Journal::open(&journal_path).unwrap();
+        journal.record(&JournalEntry::Rename {
+            from: temp_dir.path().join("a (1).pdf"),
+            to: moved.clone(),
+            size: 3,
+            digest: digest(b"old"),
+        }).unwrap();
+
+        let result = undo_journal(&journal_path, |_| {});
+        assert!(result.unwrap_err().to_string().contains("should be a file of 3 bytes"));
+        assert!(moved.exists());
+
+        // The same size is not enough
+        fs::write(&moved, b"new").unwrap();
+        let result = undo_journal(&journal_path, |_| {});
+        assert!(result.unwrap_err().to_string().contains("is not the file that was moved there"));
+        assert!(moved.exists());
+    }
+
+    #[test]
+    fn test_undo_after_partial_undo_skips_reversed_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let gone = dir.join("gone");
+        fs::write(dir.join("x.pdf"), b"x").unwrap();
+        fs::write(dir.join("c.pdf"), b"c").unwrap();
+
+        let journal_path = dir.join("journal.log");
+        let mut journal = Journal::open(&journal_path).unwrap();
+        // x.pdf came from a folder that has since been deleted
+        journal.record(&JournalEntry::Rename {
+            from: gone.join("x.pdf"),
+            to: dir.join("x.pdf"),
+            size: 1,
+            digest: digest(b"x"),
+        }).unwrap();
+        journal.record(&JournalEntry::Rename {
+            from: dir.join("c (1).pdf"),
+            to: dir.join("c.pdf"),
+            size: 1,
+            digest: digest(b"c"),
+        }).unwrap();
+
+        assert!(undo_journal(&journal_path, |_| {}).is_err());
+        assert!(dir.join("c (1).pdf").exists());
+        assert_eq!(read_journal(&journal_path).unwrap().len(), 1);
+
+        fs::create_dir(&gone).unwrap();
+        assert_eq!(undo_journal(&journal_path, |_| {}).unwrap(), 1);
+        assert!(gone.join("x.pdf").exists());
+        assert!(read_journal(&journal_path).unwrap().is_empty());
+    }
+}
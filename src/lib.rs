@@ -1,101 +1,138 @@
 use std::{
-    collections::BinaryHeap,
     error::Error,
+    fs,
     path::{Path, PathBuf},
 };
 
-use glob::glob;
-use regex::Regex;
+use glob::{glob_with, MatchOptions};
 
+mod action;
+mod apply;
+mod archive;
+mod catalog;
+mod config;
+mod dedup;
+mod error;
 mod file_hash;
 mod file_util;
-
-use crate::file_util::get_creation_time;
-use crate::file_hash::file_hash;
+mod image_hash;
+mod journal;
+mod manifest;
+mod partial;
+mod pipeline;
+mod plan_file;
+mod preserve;
+mod prune;
+mod script;
+mod throttle;
+mod watch;
+
+pub use crate::action::{parse_plan, parse_script, Action, Guard, Script, ScriptGroup};
+pub use crate::apply::{check_guards, check_plan, Applier};
+pub use crate::catalog::{Catalog, CatalogEntry, CatalogGroup, CatalogHasher};
+pub use crate::config::{Config, Profile};
+pub use crate::dedup::{
+    DedupReport,
+    Deduplicator,
+    DeduplicatorBuilder,
+    DuplicateGroup,
+    GroupMember,
+    KeepPolicy,
+    DEFAULT_COPY_PATTERN,
+};
+pub use crate::error::DedupError;
+pub use crate::file_hash::{
+    Blake3Hasher,
+    ContentHasher,
+    Digester,
+    HashAlgorithm,
+    Sha256Hasher,
+    Xxh3Hasher,
+};
+pub use crate::image_hash::{
+    near_duplicate_groups,
+    near_duplicate_images,
+    NearDuplicateGroup,
+};
+pub use crate::journal::{read_journal, undo_journal, Journal, JournalEntry};
+pub use crate::manifest::{
+    hash_files,
+    hash_files_with,
+    parse_manifest_line,
+    read_manifest,
+    write_manifest,
+    ManifestHasher,
+};
+pub use crate::partial::{plan_partial_downloads, process_partial_downloads};
+pub use crate::pipeline::HashPipeline;
+pub use crate::plan_file::{PlanEntry, PlanFile, PlanGroup, PlanOutcome, Verb, PLAN_HEADER};
+pub use crate::preserve::Preserve;
+pub use crate::prune::{plan_prune, PruneReport};
+pub use crate::script::{render_guarded, render_script_footer, render_script_header};
+pub use crate::file_util::lower_priority;
+pub use crate::throttle::{Bandwidth, PacedReads, Throttle};
+pub use crate::watch::{watch, WatchOptions};
 
 pub type MyResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 
 pub fn files_matching_pattern(dir: &str, pattern: &str) -> MyResult<Vec<PathBuf>>
 {
-    let glob_pattern = format!("{dir}/{pattern}");
-    let paths = glob(&glob_pattern)
+    files_matching_pattern_with(dir, pattern, MatchOptions::new())
+}
+
+pub(crate) fn files_matching_pattern_with(dir: &str, pattern: &str, options: MatchOptions) -> MyResult<Vec<PathBuf>>
+{
+    // The directory is taken literally, even if it contains glob characters
+    let glob_pattern = format!("{}/{pattern}", glob::Pattern::escape(dir));
+    let paths = glob_with(&glob_pattern, options)
         .map_err(|e| format!("Invalid glob pattern '{}': {}", glob_pattern, e))?
         .flatten()
         .collect();
     Ok(paths)
 }
 
-pub fn process(path: &Path, ext: &str, all_files: &[PathBuf]) -> MyResult<String> {
-    let name: String = path.file_stem()
-        .ok_or_else(|| format!("Invalid file path: {}", path.display()))?
-        .to_string_lossy()
-        .into_owned();
-    let name_re: String = name
-        .replace('(', r"\(")
-        .replace(')', r"\)");
-    let orig_path_str: &str = path.to_str()
-        .ok_or_else(|| format!("Path contains invalid UTF-8: {}", path.display()))?;
-    let regex_str: String = format!(r"{name_re} \(\d+\){ext}");
-    let re: Regex = Regex::new(&regex_str)
-        .map_err(|e| format!("Failed to compile regex '{}': {}", regex_str, e))?;
-    let files: Vec<PathBuf> = all_files
-        .iter()
-        .filter(|p: &&PathBuf| {
-            if let Some(path_str) = p.to_str() {
-                return re.is_match(path_str);
-            }
-            false
-        })
-        .cloned()
-        .collect();
-
-    let mut result: Vec<String> = vec![];
-    if !files.is_empty() {
-        // BLAKE3 hash of base file
-        let orig_hash: String = file_hash(path)
-            .map_err(|e| format!("Failed to hash {}: {}", path.display(), e))?;
-        result.push(
-            format!("# {} {} {}", "-".repeat(30), path.display(), orig_hash)
-        );
-        let mut heap = BinaryHeap::new();
-
-        for file_path in files {
-            let copy_hash: String = file_hash(&file_path)
-                .map_err(|e| format!("Failed to hash {}: {}", file_path.display(), e))?;
-            result.push(
-                format!("# {} {}", file_path.display(), copy_hash)
-            );
-            if copy_hash == orig_hash {
-                result.push(
-                    format!("rm \"{}\" # {}", file_path.display(), orig_path_str)
-                );
-            } else {
-                let creation_time = get_creation_time(&file_path)
-                    .map_err(|e| format!("Failed to get creation time for {}: {}", file_path.display(), e))?;
-                if let Some(x) = file_path.to_str() {
-                    heap.push((creation_time, x.to_owned()));
-                }
+// Every regular file below `dir`, at any depth. Symlinks are not followed,
+// so a link back up the tree cannot make the walk loop.
+pub fn files_recursive(dir: &Path) -> MyResult<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        // Unreadable directories are skipped, as glob does
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => pending.push(entry.path()),
+                Ok(kind) if kind.is_file() => files.push(entry.path()),
+                _ => {}
             }
         }
-
-        // Store file paths in a max-heap that is sorted by file creation date.
-        // The file path with the most recent creation will be at the root.
-        // Save that one and delete all others.
-        if let Some(max_val) = heap.pop() {
-            result.push(
-                format!("rm \"{}\"", orig_path_str)
-            );
-            while let Some(other_val) = heap.pop() {
-                result.push(
-                    format!("rm \"{}\"", other_val.1)
-                );
-            }
-            result.push(
-                format!("mv \"{}\" \"{}\"", max_val.1, orig_path_str)
-            );
-        }
     }
-    Ok(result.join("\n"))
+    files.sort();
+    Ok(files)
+}
+
+pub fn process(path: &Path, ext: &str, all_files: &[PathBuf]) -> MyResult<String> {
+    let actions = plan(path, ext, all_files)?;
+    Ok(render_actions(&actions))
+}
+
+pub fn render_actions(actions: &[Action]) -> String {
+    actions.iter()
+        .map(|action| action.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Decide what to do with `path` and its numbered copies among `all_files`,
+// using the default naming pattern, keep policy and hasher.
+pub fn plan(path: &Path, ext: &str, all_files: &[PathBuf]) -> MyResult<Vec<Action>> {
+    let dedup = Deduplicator::builder().filetype(ext).build()?;
+    Ok(dedup
+        .plan_group(path, ext, all_files)?
+        .map(|group| group.actions)
+        .unwrap_or_default())
 }
 
 #[cfg(test)]
@@ -273,6 +310,58 @@ mod tests {
         assert!(rm_count >= 2, "Expected at least 2 rm commands, got {}", rm_count);
     }
 
+    #[test]
+    fn test_process_truncated_copy_loses_to_complete_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+
+        // Base file is complete
+        let base = dir_path.join("report.pdf");
+        {
+            let mut f = File::create(&base).unwrap();
+            f.write_all(b"complete report").unwrap();
+        }
+
+        // Newer copy is an interrupted download of the same file
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let partial = dir_path.join("report (1).pdf");
+        {
+            let mut f = File::create(&partial).unwrap();
+            f.write_all(b"complete").unwrap();
+        }
+
+        let files = vec![base.clone(), partial.clone()];
+        let result = process(&base, ".pdf", &files).unwrap();
+
+        assert!(result.contains("report (1).pdf' # truncated copy of"));
+        assert!(!result.contains("mv"));
+    }
+
+    #[test]
+    fn test_process_truncated_base_is_replaced() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+
+        // Base file is an interrupted download
+        let base = dir_path.join("report.pdf");
+        {
+            let mut f = File::create(&base).unwrap();
+            f.write_all(b"complete").unwrap();
+        }
+
+        let full = dir_path.join("report (1).pdf");
+        {
+            let mut f = File::create(&full).unwrap();
+            f.write_all(b"complete report").unwrap();
+        }
+
+        let files = vec![base.clone(), full.clone()];
+        let result = process(&base, ".pdf", &files).unwrap();
+
+        assert!(result.contains("report.pdf' # truncated copy of"));
+        assert!(result.contains("mv"));
+    }
+
     #[test]
     fn test_process_with_special_chars_in_filename() {
         let temp_dir = TempDir::new().unwrap();
@@ -1,14 +1,55 @@
 use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufWriter, Write},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use clap::Parser;
-use rayon::prelude::*;
+use clap::{Parser, Subcommand, ValueEnum};
 
 use file_dup::{
-    process,
-    files_matching_pattern,
+    check_guards,
+    check_plan,
+    hash_files,
+    hash_files_with,
+    lower_priority,
+    near_duplicate_groups,
+    near_duplicate_images,
+    parse_script,
+    plan_prune,
+    read_manifest,
+    render_actions,
+    render_guarded,
+    render_script_footer,
+    render_script_header,
+    undo_journal,
+    watch,
+    write_manifest,
+    Action,
+    Applier,
+    Bandwidth,
+    Catalog,
+    CatalogHasher,
+    Config,
+    ContentHasher,
+    DedupError,
+    DedupReport,
+    Deduplicator,
+    HashAlgorithm,
+    HashPipeline,
+    Journal,
+    JournalEntry,
+    KeepPolicy,
+    ManifestHasher,
     MyResult,
+    PlanFile,
+    PlanOutcome,
+    Script,
+    Preserve,
+    Profile,
+    WatchOptions,
+    PLAN_HEADER,
 };
 
 #[derive(Parser, Debug)]
@@ -16,7 +57,315 @@ use file_dup::{
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(author = "Hugh Brown <hughdbrown@gmail.com>")]
 #[command(about = "File deduplicator")]
+#[command(args_conflicts_with_subcommands = true)]
 struct AppArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Without a subcommand the tool behaves like `plan`
+    #[command(flatten)]
+    plan: PlanArgs,
+}
+
+// Options shared by every command that scans a directory for copies.
+#[derive(clap::Args, Debug)]
+struct ScanOptions {
+    /// Named profile from the config file to start from
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Config file [default: ~/.config/file-dup/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// File extension to search for [default: .pdf]
+    #[arg(short, long)]
+    filetype: Option<String>,
+
+    /// Directory to scan [default: .]
+    #[arg(short, long)]
+    dir: Option<String>,
+
+    /// Which file survives when copies differ: newest, oldest or base [default: newest]
+    #[arg(long)]
+    keep: Option<KeepPolicy>,
+
+    /// Regex for the copy suffix between name and extension [default: " \(\d+\)"]
+    #[arg(long)]
+    copy_pattern: Vec<String>,
+
+    /// Ignore files whose names match this glob
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Content hash used to compare files: blake3, sha256 or xxh3 [default: blake3]
+    #[arg(long)]
+    hash: Option<HashAlgorithm>,
+
+    /// Reuse digests from a b3sum/sha256sum manifest instead of re-hashing
+    #[arg(long)]
+    from_manifest: Option<PathBuf>,
+
+    /// Record every scanned file in this SQLite catalog and reuse its digests
+    #[arg(long)]
+    catalog: Option<PathBuf>,
+
+    /// Stop at the first unreadable file instead of reporting it at the end
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Match extensions and base names regardless of case
+    #[arg(long, overrides_with = "no_ignore_case")]
+    ignore_case: bool,
+
+    /// Match names as written, even if the profile ignores case
+    #[arg(long, overrides_with = "ignore_case")]
+    no_ignore_case: bool,
+
+    /// Metadata a copy renamed over its base takes from the group: any of mode,times,xattrs
+    #[arg(long, value_name = "LIST")]
+    preserve: Option<Preserve>,
+
+    /// Scan the files symlinks point to instead of leaving symlinks alone
+    #[arg(long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+
+    /// Leave symlinks alone, even if the profile follows them
+    #[arg(long, overrides_with = "follow_symlinks")]
+    no_follow_symlinks: bool,
+
+    /// Skip files on a different file system than the directory being scanned
+    #[arg(long, overrides_with = "no_one_file_system")]
+    one_file_system: bool,
+
+    /// Scan across file systems, even if the profile stays on one
+    #[arg(long, overrides_with = "one_file_system")]
+    no_one_file_system: bool,
+
+    /// Threads reading files to hash [default: 4]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    io_threads: Option<u32>,
+
+    /// Threads hashing the data read [default: one per CPU]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    hash_threads: Option<u32>,
+
+    /// Run at the lowest CPU and I/O priority, without filling the page cache
+    #[arg(long, overrides_with = "no_nice")]
+    nice: bool,
+
+    /// Run at normal priority, even if the profile sets nice
+    #[arg(long, overrides_with = "nice")]
+    no_nice: bool,
+
+    /// Cap the combined read rate, e.g. 50M (bytes per second; K, M, G suffixes)
+    #[arg(long, value_name = "RATE")]
+    bwlimit: Option<Bandwidth>,
+}
+
+// A `--flag`/`--no-flag` pair: None when neither is given, so the profile's
+// setting stands.
+fn switch(on: bool, off: bool) -> Option<bool> {
+    if on { Some(true) } else if off { Some(false) } else { None }
+}
+
+impl ScanOptions {
+    // The flags given on the command line, as a profile that overrides the config.
+    fn overrides(&self) -> Profile {
+        Profile {
+            roots: self.dir.iter().map(PathBuf::from).collect(),
+            filetypes: self.filetype.iter().cloned().collect(),
+            copy_patterns: self.copy_pattern.clone(),
+            excludes: self.exclude.clone(),
+            keep: self.keep,
+            hash: self.hash,
+            ignore_case: switch(self.ignore_case, self.no_ignore_case),
+            preserve: self.preserve,
+            follow_symlinks: switch(self.follow_symlinks, self.no_follow_symlinks),
+            one_file_system: switch(self.one_file_system, self.no_one_file_system),
+            io_threads: self.io_threads.map(|n| n as usize),
+            hash_threads: self.hash_threads.map(|n| n as usize),
+            nice: switch(self.nice, self.no_nice),
+            bwlimit: self.bwlimit,
+        }
+    }
+
+    // The effective settings: defaults, then the selected profile, then flags.
+    fn settings(&self) -> MyResult<Profile> {
+        let base = match &self.profile {
+            Some(name) => {
+                let path = self.config.clone().or_else(Config::default_path)
+                    .ok_or("Cannot locate the config file; pass --config")?;
+                Config::load(&path)?.profile(name)?.clone()
+            }
+            None => Profile::default(),
+        };
+        Ok(base.merge(&self.overrides()).resolved())
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct PlanArgs {
+    #[command(flatten)]
+    scan: ScanOptions,
+
+    /// Also report visually similar images (perceptual hash)
+    #[arg(long)]
+    images: bool,
+
+    /// Maximum Hamming distance between image hashes to count as near-duplicates
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(0..=64))]
+    max_distance: u32,
+
+    /// Emit rm commands for near-duplicate images (requires --images)
+    #[arg(long, requires = "images")]
+    delete_near_duplicates: bool,
+
+    /// Write an editable plan to this file instead of printing a script
+    #[arg(short, long, conflicts_with = "images")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Hash every matching file into the catalog given with --catalog
+    Scan(ScanArgs),
+    /// Print the cleanup script (the default when no subcommand is given)
+    Plan(PlanArgs),
+    /// Carry out a plan written by `plan`, moving removed files into quarantine
+    Apply(ApplyArgs),
+    /// Print statistics about the duplicates found
+    Report(ReportArgs),
+    /// Check that a plan written by `plan` still matches the files on disk
+    Verify(VerifyArgs),
+    /// Watch a directory and deduplicate numbered copies as they arrive
+    Watch(WatchArgs),
+    /// Write a b3sum/sha256sum-compatible manifest of the scanned files
+    Hash(HashArgs),
+    /// Remove files whose content already exists in a reference tree or manifest
+    Prune(PruneArgs),
+    /// Report duplicate groups recorded in a catalog
+    Query(QueryArgs),
+    /// Reverse the actions recorded in a journal written by a native apply
+    Undo(UndoArgs),
+    /// Inspect the configuration file and its profiles
+    Config(ConfigArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the settings a scan would use, after the profile and flags are applied
+    Show(ScanArgs),
+}
+
+fn run_config(args: &ConfigArgs) -> MyResult<()> {
+    match &args.command {
+        ConfigCommand::Show(args) => {
+            let settings = args.scan.settings()?;
+            print!("{}", toml::to_string(&settings)?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args, Debug)]
+struct UndoArgs {
+    /// Journal to reverse, newest action first
+    journal: PathBuf,
+}
+
+fn run_undo(args: &UndoArgs) -> MyResult<()> {
+    let count = undo_journal(&args.journal, |entry| match entry {
+        JournalEntry::Remove { path, quarantined, .. } => {
+            println!("restored {} from {}", path.display(), quarantined.display())
+        }
+        JournalEntry::Trash { path, trashed, .. } => {
+            println!("restored {} from {}", path.display(), trashed.display())
+        }
+        JournalEntry::Rename { from, to, .. } => {
+            println!("renamed {} back to {}", to.display(), from.display())
+        }
+        JournalEntry::Exchange { from, to, .. } => {
+            println!("swapped {} back with {}", to.display(), from.display())
+        }
+    })?;
+    println!("# Undid {} action(s) from {}", count, args.journal.display());
+    Ok(())
+}
+
+#[derive(clap::Args, Debug)]
+struct QueryArgs {
+    /// SQLite catalog written by a run with --catalog
+    #[arg(long)]
+    catalog: PathBuf,
+
+    /// Algorithm the catalog digests were made with: blake3, sha256 or xxh3
+    #[arg(long, default_value = "blake3")]
+    hash: HashAlgorithm,
+}
+
+fn run_query(args: &QueryArgs) -> MyResult<()> {
+    if !args.catalog.is_file() {
+        return Err(format!("Catalog does not exist: {}", args.catalog.display()).into());
+    }
+    let catalog = Catalog::open(&args.catalog)?;
+    let groups = catalog.duplicate_groups(args.hash.hasher().name())?;
+    let reclaimable: u64 = groups.iter().map(|g| g.reclaimable()).sum();
+    println!(
+        "# Catalog {}: {} duplicate groups, {} bytes reclaimable",
+        args.catalog.display(), groups.len(), reclaimable
+    );
+    for group in &groups {
+        println!(
+            "# {} {} {} files ({} names) x {} bytes",
+            "-".repeat(30), group.digest, group.inodes, group.paths.len(), group.size
+        );
+        for path in &group.paths {
+            println!("# {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args, Debug)]
+struct PruneArgs {
+    /// Reference directory or checksum manifest; never modified
+    #[arg(long)]
+    reference: PathBuf,
+
+    /// Directory to clean up, searched recursively
+    target: PathBuf,
+
+    /// Only consider target files with this extension
+    #[arg(short, long)]
+    filetype: Option<String>,
+
+    /// Content hash to compare with: blake3, sha256 or xxh3
+    #[arg(long, default_value = "blake3")]
+    hash: HashAlgorithm,
+}
+
+// Returns the number of files that could not be hashed.
+fn run_prune(args: &PruneArgs) -> MyResult<usize> {
+    println!("# Pruning {} against {}", args.target.display(), args.reference.display());
+    let hasher = args.hash.hasher();
+    let report = plan_prune(&args.reference, &args.target, args.filetype.as_deref(), hasher.as_ref())?;
+    let script = render_actions(&report.actions);
+    if !script.is_empty() {
+        println!("{}", script);
+    }
+    report_errors(&report.errors);
+    Ok(report.errors.len())
+}
+
+#[derive(clap::Args, Debug)]
+struct HashArgs {
     /// File extension to search for
     #[arg(short, long, default_value = ".pdf")]
     filetype: String,
@@ -24,21 +373,154 @@ struct AppArgs {
     /// Directory to scan
     #[arg(short, long, default_value = ".")]
     dir: String,
+
+    /// Ignore files whose names match this glob
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Content hash to write: blake3, sha256 or xxh3
+    #[arg(long, default_value = "blake3")]
+    hash: HashAlgorithm,
+
+    /// Write the manifest to this file instead of stdout
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Reuse digests from an existing manifest instead of re-hashing
+    #[arg(long)]
+    from_manifest: Option<PathBuf>,
+}
+
+fn content_hasher(hash: HashAlgorithm, from_manifest: Option<&Path>) -> MyResult<Arc<dyn ContentHasher>> {
+    Ok(match from_manifest {
+        Some(path) => Arc::new(ManifestHasher::new(read_manifest(path)?, hash.hasher())),
+        None => hash.hasher(),
+    })
 }
 
-fn validate_args(args: &AppArgs) -> MyResult<()> {
-    // Validate that filetype starts with a dot
-    if !args.filetype.starts_with('.') {
-        return Err(format!("File extension must start with a dot (e.g., '.pdf'), got '{}'", args.filetype).into());
+// Returns the number of files that could not be hashed.
+fn run_hash(args: &HashArgs) -> MyResult<usize> {
+    let mut builder = Deduplicator::builder()
+        .root(&args.dir)
+        .filetype(&args.filetype)
+        .hasher(content_hasher(args.hash, args.from_manifest.as_deref())?);
+    for pattern in &args.exclude {
+        builder = builder.exclude(pattern);
     }
+    let dedup = builder.build()?;
 
-    // Validate that directory exists and is readable
-    let dir_path = Path::new(&args.dir);
-    if !dir_path.exists() {
-        return Err(format!("Directory does not exist: {}", args.dir).into());
+    let files = dedup.scan()?;
+    let (entries, errors) = hash_files(&files, dedup.hasher());
+    // Absolute names, so the manifest means the same wherever it is read from
+    let mut entries: Vec<(PathBuf, String)> = entries
+        .into_iter()
+        .map(|(path, digest)| (std::path::absolute(&path).unwrap_or(path), digest))
+        .collect();
+    entries.sort();
+
+    match &args.manifest {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("Failed to create manifest {}: {}", path.display(), e))?;
+            let mut out = BufWriter::new(file);
+            write_manifest(&mut out, &entries)?;
+            out.flush()?;
+        }
+        None => write_manifest(&mut io::stdout().lock(), &entries)?,
     }
-    if !dir_path.is_dir() {
-        return Err(format!("Path is not a directory: {}", args.dir).into());
+
+    report_errors(&errors);
+    Ok(errors.len())
+}
+
+#[derive(clap::Args, Debug)]
+struct WatchArgs {
+    /// Directory to watch
+    #[arg(id = "watch_dir", value_name = "DIR")]
+    dir: PathBuf,
+
+    #[command(flatten)]
+    scan: ScanOptions,
+
+    /// Seconds a new file must go without writes before it is compared
+    #[arg(long, default_value_t = 5)]
+    settle: u64,
+
+    /// What to do with duplicates that are found
+    #[arg(long, value_enum, default_value_t = WatchPolicy::Report)]
+    policy: WatchPolicy,
+
+    /// Append the action log to this file instead of stdout
+    #[arg(long)]
+    log: Option<PathBuf>,
+
+    /// Directory that removed files are moved into [default: DIR/.file-dup-trash]
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    /// Journal of applied actions, for undo [default: QUARANTINE/journal.log]
+    #[arg(long)]
+    journal: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum WatchPolicy {
+    /// Log the actions that would be taken
+    Report,
+    /// Remove and rename files, logging each action
+    Apply,
+}
+
+fn run_watch(args: &WatchArgs) -> MyResult<()> {
+    if !args.dir.is_dir() {
+        return Err(format!("Path is not a directory: {}", args.dir.display()).into());
+    }
+    let mut log: Box<dyn Write> = match &args.log {
+        Some(path) => Box::new(
+            OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| format!("Failed to open log {}: {}", path.display(), e))?
+        ),
+        None => Box::new(io::stdout()),
+    };
+    // The settings `plan` would use, for the watched directory alone
+    if args.scan.dir.is_some() {
+        return Err("watch takes the directory to watch as its DIR argument, not --dir".into());
+    }
+    let mut settings = args.scan.settings()?;
+    settings.roots = vec![args.dir.clone()];
+    let scan = Scan::with_settings(&args.scan, settings)?;
+    let mut options = WatchOptions::new(
+        args.dir.clone(),
+        scan.dedup,
+        Duration::from_secs(args.settle),
+        args.policy == WatchPolicy::Apply,
+    );
+    if let Some(quarantine) = &args.quarantine {
+        options.journal = quarantine.join("journal.log");
+        options.quarantine = quarantine.clone();
+    }
+    if let Some(journal) = &args.journal {
+        options.journal = journal.clone();
+    }
+    watch(&options, &mut log)
+}
+
+fn validate_args(settings: &Profile) -> MyResult<()> {
+    // Validate that filetypes start with a dot
+    for ext in &settings.filetypes {
+        if !ext.starts_with('.') {
+            return Err(format!("File extension must start with a dot (e.g., '.pdf'), got '{}'", ext).into());
+        }
+    }
+
+    // Validate that directories exist and are readable
+    for dir_path in &settings.roots {
+        if !dir_path.exists() {
+            return Err(format!("Directory does not exist: {}", dir_path.display()).into());
+        }
+        if !dir_path.is_dir() {
+            return Err(format!("Path is not a directory: {}", dir_path.display()).into());
+        }
     }
 
     Ok(())
@@ -52,88 +534,375 @@ fn collapse_strings(result: &[String]) -> String {
         .join("\n")
 }
 
-fn run_parallel(files: &[PathBuf], ext: &str) -> MyResult<()> {
-    // Create a lookup map for faster file stem access
-    let file_stems: Vec<_> = files.iter()
-        .map(|path| {
-            path.file_stem()
-                .and_then(|s| s.to_str())
-                .unwrap_or("")
-                .to_string()
-        })
-        .collect();
+// Exit code for a run that finished but skipped files it could not process
+const EXIT_FILE_ERRORS: i32 = 2;
 
-    // Calculate chunk size based on number of files and available CPUs
-    let chunk_size = std::cmp::max(
-        1,
-        files.len() / rayon::current_num_threads().max(1)
-    );
+fn main() {
+    let app = AppArgs::parse();
 
-    let result: Result<Vec<String>, _> = files.par_iter()
-        .with_min_len(chunk_size) // Adaptive chunk size
-        .map(|path: &PathBuf| -> MyResult<String> {
-            let path_idx = files.iter().position(|p| p == path).unwrap_or(0);
-            let prefix = &file_stems[path_idx];
-
-            // Pre-filter the files to avoid repeated string operations
-            let copy: Vec<PathBuf> = files.iter()
-                .enumerate()
-                .filter_map(|(idx, pb)| {
-                    if file_stems[idx].starts_with(prefix) {
-                        Some(pb.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            process(path, ext, &copy)
-        })
-        .collect();
+    match run(&app) {
+        Ok(0) => {}
+        Ok(_) => std::process::exit(EXIT_FILE_ERRORS),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let result = result?;
-    println!("{}", collapse_strings(&result));
-    Ok(())
+fn report_errors(errors: &[DedupError]) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!("{} file(s) could not be processed; their groups were skipped:", errors.len());
+    for e in errors {
+        eprintln!("  {}", e);
+    }
 }
 
-fn main() {
-    let app = AppArgs::parse();
+// Returns the number of files that could not be processed.
+fn run(app: &AppArgs) -> MyResult<usize> {
+    match &app.command {
+        Some(Command::Scan(args)) => run_scan(args),
+        Some(Command::Plan(args)) => run_plan(args),
+        Some(Command::Apply(args)) => run_apply(args),
+        Some(Command::Report(args)) => run_report(args),
+        Some(Command::Verify(args)) => run_verify(args),
+        Some(Command::Watch(args)) => {
+            run_watch(args)?;
+            Ok(0)
+        }
+        Some(Command::Hash(args)) => run_hash(args),
+        Some(Command::Prune(args)) => run_prune(args),
+        Some(Command::Query(args)) => {
+            run_query(args)?;
+            Ok(0)
+        }
+        Some(Command::Undo(args)) => {
+            run_undo(args)?;
+            Ok(0)
+        }
+        Some(Command::Config(args)) => {
+            run_config(args)?;
+            Ok(0)
+        }
+        None => run_plan(&app.plan),
+    }
+}
 
-    if let Err(e) = run(&app) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+// A scan configured from the shared options, its hasher, and the catalog
+// that hasher writes to, if any.
+struct Scan {
+    settings: Profile,
+    dedup: Deduplicator,
+    hasher: Arc<dyn ContentHasher>,
+    catalog: Option<Arc<Catalog>>,
+}
+
+impl Scan {
+    fn new(opts: &ScanOptions) -> MyResult<Self> {
+        Self::with_settings(opts, opts.settings()?)
+    }
+
+    // As `new`, with settings already taken from `opts` and adjusted.
+    fn with_settings(opts: &ScanOptions, settings: Profile) -> MyResult<Self> {
+        validate_args(&settings)?;
+        // The main thread too, before any threads start, so they inherit it
+        if settings.nice == Some(true)
+            && let Err(e) = lower_priority()
+        {
+            eprintln!("Warning: could not lower the priority: {}", e);
+        }
+
+        let mut hasher = content_hasher(settings.hash.unwrap_or_default(), opts.from_manifest.as_deref())?;
+        let catalog = match &opts.catalog {
+            Some(path) => {
+                let catalog = Arc::new(Catalog::open(path)?);
+                hasher = Arc::new(CatalogHasher::new(catalog.clone(), hasher));
+                Some(catalog)
+            }
+            None => None,
+        };
+        let dedup = settings.builder()
+            .hasher(hasher.clone())
+            .fail_fast(opts.fail_fast)
+            .build()?;
+        Ok(Scan { settings, dedup, hasher, catalog })
+    }
+
+    // Run the deduplicator, printing its warnings to stderr.
+    fn run(&self) -> MyResult<DedupReport> {
+        let report = self.dedup.run()?;
+        for warning in &report.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        Ok(report)
+    }
+
+    fn roots(&self) -> String {
+        let roots: Vec<String> = self.settings.roots.iter().map(|r| r.display().to_string()).collect();
+        roots.join(", ")
+    }
+
+    fn filetypes(&self) -> String {
+        self.settings.filetypes.join(", ")
+    }
+
+    // Record every file in the catalog, not only the ones a run hashed.
+    fn update_catalog(&self, files: &[PathBuf]) -> MyResult<Vec<DedupError>> {
+        let Some(catalog) = &self.catalog else {
+            return Ok(vec![]);
+        };
+        let pipeline = HashPipeline { io_threads: self.settings.io_threads, hash_threads: self.settings.hash_threads };
+        let _paced = self.dedup.pace_reads();
+        let (_, errors) = hash_files_with(files, self.hasher.as_ref(), pipeline);
+        for root in &self.settings.roots {
+            catalog.forget_missing(root)?;
+        }
+        Ok(errors)
     }
 }
 
-fn run(app: &AppArgs) -> MyResult<()> {
-    // Validate arguments
-    validate_args(app)?;
+#[derive(clap::Args, Debug)]
+struct ScanArgs {
+    #[command(flatten)]
+    scan: ScanOptions,
+}
 
-    // Find all the files that have the required extension.
-    // Make this fast by scanning the disk only once.
-    let pattern = format!("*{}", app.filetype);
+// Returns the number of files that could not be hashed.
+fn run_scan(args: &ScanArgs) -> MyResult<usize> {
+    let Some(catalog_path) = &args.scan.catalog else {
+        return Err("scan needs --catalog to store the index".into());
+    };
+    let scan = Scan::new(&args.scan)?;
+    let files = scan.dedup.scan()?;
+    let errors = scan.update_catalog(&files)?;
+    println!(
+        "# Indexed {} {} files from {} into {}",
+        files.len() - errors.len(), scan.filetypes(), scan.roots(), catalog_path.display()
+    );
+    report_errors(&errors);
+    Ok(errors.len())
+}
+
+// Returns the number of files that could not be processed.
+fn run_plan(args: &PlanArgs) -> MyResult<usize> {
+    let opts = &args.scan;
+    let scan = Scan::new(opts)?;
+
+    if args.output.is_none() {
+        let arguments: Vec<String> = std::env::args().collect();
+        println!("{}", render_script_header(&arguments, scan.hasher.name()));
+    }
 
     // Scan for files
-    println!("# Scanning for files in {}...", app.dir);
-    let files = files_matching_pattern(&app.dir, &pattern)?;
-    println!("# Processing {} {} files", files.len(), &app.filetype);
+    println!("# Scanning for files in {}...", scan.roots());
+    let report = scan.run()?;
+    let files = &report.files;
+    println!("# Processing {} {} files", files.len(), scan.filetypes());
 
     if files.is_empty() {
-        println!("No matching files found. Check the directory path and file extension.");
-        return Ok(());
+        println!("# No matching files found. Check the directory path and file extension.");
+        return Ok(0);
     }
 
-    // Set optimal thread count based on CPU cores and workload
-    let num_cpus = num_cpus::get();
-    let thread_count = std::cmp::min(num_cpus, std::cmp::max(1, files.len() / 10));
+    if let Some(output) = &args.output {
+        let algorithm = scan.settings.hash.unwrap_or_default();
+        let (mut plan, plan_errors) = PlanFile::from_report(&report, scan.hasher.as_ref(), algorithm);
+        plan.preserve = scan.settings.preserve.unwrap_or_default();
+        let file = File::create(output)
+            .map_err(|e| format!("Failed to create plan {}: {}", output.display(), e))?;
+        let mut out = BufWriter::new(file);
+        plan.write(&mut out)?;
+        out.flush()?;
+        println!("# Wrote {} group(s) to {}", plan.groups.len(), output.display());
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(thread_count)
-        .build()
-        .map_err(|e| format!("Failed to build thread pool: {}", e))?
-        .install(|| run_parallel(&files, &app.filetype))?;
+        let mut errors = report.errors;
+        errors.extend(plan_errors);
+        errors.extend(scan.update_catalog(files)?);
+        report_errors(&errors);
+        return Ok(errors.len());
+    }
 
-    Ok(())
+    let (script, mut script_errors) = render_guarded(&report, scan.hasher.as_ref());
+    let mut blocks = vec![script];
+    if args.images {
+        let _paced = scan.dedup.pace_reads();
+        let (groups, warnings) = near_duplicate_images(files, args.max_distance, scan.hasher.as_ref());
+        for warning in &warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        blocks.push(format!("# Found {} groups of near-duplicate images", groups.len()));
+        let images = DedupReport {
+            groups: near_duplicate_groups(&groups, args.delete_near_duplicates),
+            ..DedupReport::default()
+        };
+        let (script, errors) = render_guarded(&images, scan.hasher.as_ref());
+        blocks.push(script);
+        script_errors.extend(errors);
+    }
+    blocks.push(render_script_footer());
+    println!("{}", collapse_strings(&blocks));
+
+    let mut errors = report.errors;
+    errors.extend(script_errors);
+    errors.extend(scan.update_catalog(files)?);
+    report_errors(&errors);
+    Ok(errors.len())
+}
+
+#[derive(clap::Args, Debug)]
+struct ReportArgs {
+    #[command(flatten)]
+    scan: ScanOptions,
+}
+
+// Returns the number of files that could not be processed.
+fn run_report(args: &ReportArgs) -> MyResult<usize> {
+    let scan = Scan::new(&args.scan)?;
+    let report = scan.run()?;
+    let count = |f: fn(&Action) -> bool| report.actions().filter(|a| f(a)).count();
+
+    println!("# Report for {} files in {}", scan.filetypes(), scan.roots());
+    println!("files scanned:       {}", report.files.len());
+    println!("duplicate groups:    {}", report.groups.len());
+    println!("files to remove:     {}", count(|a| matches!(a, Action::Remove { .. })));
+    println!("files to rename:     {}", count(|a| matches!(a, Action::Rename { .. })));
+    println!("already linked:      {}", report.linked().count());
+    println!("reclaimable bytes:   {}", report.reclaimable());
+    println!("errors:              {}", report.errors.len());
+
+    let mut errors = report.errors;
+    errors.extend(scan.update_catalog(&report.files)?);
+    report_errors(&errors);
+    Ok(errors.len())
+}
+
+fn read_script(path: &Path) -> MyResult<Script> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read plan {}: {}", path.display(), e))?;
+    Ok(parse_script(&text).map_err(|e| format!("Invalid plan {}: {}", path.display(), e))?)
+}
+
+// Editable plans written by `plan -o` start with a header; anything else is
+// read as a script printed by `plan`.
+fn is_plan_file(path: &Path) -> MyResult<bool> {
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to read plan {}: {}", path.display(), e))?;
+    let mut first_line = String::new();
+    io::BufReader::new(file).read_line(&mut first_line)?;
+    Ok(first_line.trim_end() == PLAN_HEADER)
+}
+
+// The actions of the script's groups whose guards still hold; the others are
+// refused, as the script itself would skip them. Fails, listing every
+// problem, if those actions no longer apply cleanly.
+fn check_script(path: &Path) -> MyResult<(Vec<Action>, PlanOutcome)> {
+    let script = read_script(path)?;
+    let algorithm: HashAlgorithm = match &script.hash {
+        Some(name) => name.parse()?,
+        None => HashAlgorithm::default(),
+    };
+    let hasher = algorithm.hasher();
+    let mut actions = vec![];
+    let mut outcome = PlanOutcome::default();
+    for group in script.groups {
+        let has_changes = group.actions.iter().any(|a| !matches!(a, Action::Comment(_)));
+        match check_guards(&group.guards, hasher.as_ref()) {
+            Some(reason) if has_changes => {
+                outcome.refused.push((group.guards[0].path.display().to_string(), reason));
+            }
+            _ => {
+                outcome.applied += usize::from(has_changes);
+                actions.extend(group.actions);
+            }
+        }
+    }
+
+    let problems = check_plan(&actions);
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("  {}", problem);
+        }
+        return Err(format!("Plan {} is stale: {} problem(s)", path.display(), problems.len()).into());
+    }
+    Ok((actions, outcome))
+}
+
+fn report_refused(outcome: &PlanOutcome) {
+    if outcome.refused.is_empty() {
+        return;
+    }
+    eprintln!("{} group(s) changed since planning and were left alone:", outcome.refused.len());
+    for (title, reason) in &outcome.refused {
+        eprintln!("  {}: {}", title, reason);
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Plan written by `plan` or `plan -o`
+    plan: PathBuf,
+}
+
+// Returns the number of groups that no longer match the files.
+fn run_verify(args: &VerifyArgs) -> MyResult<usize> {
+    if is_plan_file(&args.plan)? {
+        let outcome = PlanFile::read(&args.plan)?.check();
+        println!("# Plan {}: {} group(s) valid, {} stale", args.plan.display(), outcome.applied, outcome.refused.len());
+        report_refused(&outcome);
+        return Ok(outcome.refused.len());
+    }
+    let (actions, outcome) = check_script(&args.plan)?;
+    if !outcome.refused.is_empty() {
+        println!("# Plan {}: {} group(s) valid, {} stale", args.plan.display(), outcome.applied, outcome.refused.len());
+        report_refused(&outcome);
+        return Ok(outcome.refused.len());
+    }
+    let count = actions.iter().filter(|a| !matches!(a, Action::Comment(_))).count();
+    println!("# Plan {} is valid: {} action(s)", args.plan.display(), count);
+    Ok(0)
+}
+
+#[derive(clap::Args, Debug)]
+struct ApplyArgs {
+    /// Plan written by `plan` or `plan -o`
+    plan: PathBuf,
+
+    /// Directory that removed files are moved into [default: .file-dup-trash beside the plan]
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    /// Journal of applied actions, for undo [default: QUARANTINE/journal.log]
+    #[arg(long)]
+    journal: Option<PathBuf>,
+}
+
+// Returns the number of groups refused because their files changed.
+fn run_apply(args: &ApplyArgs) -> MyResult<usize> {
+    let plan_file = is_plan_file(&args.plan)?;
+    let (script, checked) = if plan_file { Default::default() } else { check_script(&args.plan)? };
+
+    let quarantine = match &args.quarantine {
+        Some(path) => path.clone(),
+        None => args.plan.parent().unwrap_or(Path::new(".")).join(".file-dup-trash"),
+    };
+    let journal_path = args.journal.clone().unwrap_or_else(|| quarantine.join("journal.log"));
+    std::fs::create_dir_all(&quarantine)
+        .map_err(|e| format!("Failed to create quarantine {}: {}", quarantine.display(), e))?;
+    let mut applier = Applier::new(&quarantine, Journal::open(&journal_path)?)?;
+
+    let refused = if plan_file {
+        let outcome = PlanFile::read(&args.plan)?.apply(&mut applier, |entry| println!("{}", entry))?;
+        report_refused(&outcome);
+        outcome.refused.len()
+    } else {
+        applier.apply(&script, |action| println!("{}", action))?;
+        report_refused(&checked);
+        checked.refused.len()
+    };
+    println!("# Journal written to {}", applier.journal_path().display());
+    Ok(refused)
 }
 
 #[cfg(test)]
@@ -146,9 +915,10 @@ mod tests {
     #[test]
     fn test_validate_args_valid() {
         let temp_dir = TempDir::new().unwrap();
-        let args = AppArgs {
-            filetype: ".pdf".to_string(),
-            dir: temp_dir.path().to_str().unwrap().to_string(),
+        let args = Profile {
+            filetypes: vec![".pdf".to_string()],
+            roots: vec![temp_dir.path().to_path_buf()],
+            ..Profile::default()
         };
 
         assert!(validate_args(&args).is_ok());
@@ -156,9 +926,10 @@ mod tests {
 
     #[test]
     fn test_validate_args_missing_dot() {
-        let args = AppArgs {
-            filetype: "pdf".to_string(),
-            dir: ".".to_string(),
+        let args = Profile {
+            filetypes: vec!["pdf".to_string()],
+            roots: vec![PathBuf::from(".")],
+            ..Profile::default()
         };
 
         let result = validate_args(&args);
@@ -168,9 +939,10 @@ mod tests {
 
     #[test]
     fn test_validate_args_nonexistent_dir() {
-        let args = AppArgs {
-            filetype: ".pdf".to_string(),
-            dir: "/nonexistent/directory/path".to_string(),
+        let args = Profile {
+            filetypes: vec![".pdf".to_string()],
+            roots: vec![PathBuf::from("/nonexistent/directory/path")],
+            ..Profile::default()
         };
 
         let result = validate_args(&args);
@@ -184,9 +956,10 @@ mod tests {
         let file_path = temp_dir.path().join("test.txt");
         File::create(&file_path).unwrap();
 
-        let args = AppArgs {
-            filetype: ".pdf".to_string(),
-            dir: file_path.to_str().unwrap().to_string(),
+        let args = Profile {
+            filetypes: vec![".pdf".to_string()],
+            roots: vec![file_path.clone()],
+            ..Profile::default()
         };
 
         let result = validate_args(&args);
@@ -216,7 +989,7 @@ mod tests {
     }
 
     #[test]
-    fn test_run_parallel_with_real_files() {
+    fn test_render_guarded_with_real_files() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
 
@@ -227,23 +1000,31 @@ mod tests {
             f.write_all(b"content1").unwrap();
         }
 
-        let file2 = dir_path.join("test2.pdf");
+        let file2 = dir_path.join("test1 (1).pdf");
         {
             let mut f = File::create(&file2).unwrap();
-            f.write_all(b"content2").unwrap();
+            f.write_all(b"content1").unwrap();
         }
 
-        let files = vec![file1, file2];
+        let report = Deduplicator::builder()
+            .root(dir_path)
+            .filetype(".pdf")
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
 
-        // Should not panic or error
-        let result = run_parallel(&files, ".pdf");
-        assert!(result.is_ok());
+        let (result, errors) = render_guarded(&report, HashAlgorithm::Blake3.hasher().as_ref());
+        assert!(errors.is_empty());
+        assert!(result.starts_with("# ---"));
+        assert!(result.contains("rm '"));
     }
 
     #[test]
-    fn test_run_parallel_empty_list() {
-        let files: Vec<PathBuf> = vec![];
-        let result = run_parallel(&files, ".pdf");
-        assert!(result.is_ok());
+    fn test_render_guarded_empty() {
+        let report = DedupReport::default();
+        let (result, errors) = render_guarded(&report, HashAlgorithm::Blake3.hasher().as_ref());
+        assert_eq!(result, "");
+        assert!(errors.is_empty());
     }
 }
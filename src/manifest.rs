@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::error::DedupError;
+use crate::file_hash::{ContentHasher, Digester};
+use crate::pipeline::HashPipeline;
+use crate::MyResult;
+
+// Manifests use the `b3sum`/`sha256sum` line format: "<hex digest>  <path>".
+// A path containing a backslash or newline is escaped and the line is
+// prefixed with a backslash, as coreutils does.
+pub fn write_manifest<W: Write>(out: &mut W, entries: &[(PathBuf, String)]) -> io::Result<()> {
+    for (path, digest) in entries {
+        let name = path.to_string_lossy();
+        if name.contains('\\') || name.contains('\n') {
+            let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+            writeln!(out, "\\{}  {}", digest, escaped)?;
+        } else {
+            writeln!(out, "{}  {}", digest, name)?;
+        }
+    }
+    Ok(())
+}
+
+fn unescape(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+pub fn parse_manifest_line(line: &str) -> Option<(PathBuf, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (digest, rest) = line.split_once(' ')?;
+    // Text mode is "digest  name", binary mode is "digest *name"
+    let name = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+    if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) || name.is_empty() {
+        return None;
+    }
+    let name = if escaped { unescape(name) } else { name.to_owned() };
+    Some((PathBuf::from(name), digest.to_ascii_lowercase()))
+}
+
+// Hash `files` in parallel. Files that cannot be hashed are returned as errors
+// rather than stopping the others.
+pub fn hash_files(files: &[PathBuf], hasher: &dyn ContentHasher) -> (Vec<(PathBuf, String)>, Vec<DedupError>) {
+    hash_files_with(files, hasher, HashPipeline::default())
+}
+
+pub fn hash_files_with(
+    files: &[PathBuf],
+    hasher: &dyn ContentHasher,
+    pipeline: HashPipeline,
+) -> (Vec<(PathBuf, String)>, Vec<DedupError>) {
+    let mut entries = vec![];
+    let mut errors = vec![];
+    for (path, digest) in files.iter().zip(pipeline.run(files, hasher)) {
+        match digest {
+            Ok(digest) => entries.push((path.clone(), digest)),
+            Err(e) => errors.push(DedupError::hash(path, e)),
+        }
+    }
+    (entries, errors)
+}
+
+// Relative paths in a manifest are taken relative to the current directory,
+// as `b3sum --check` does.
+fn manifest_key(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Relative names are resolved against the manifest's own directory, as
+// `b3sum --check` run there would.
+pub fn read_manifest(path: &Path) -> MyResult<HashMap<PathBuf, String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read manifest {}: {}", path.display(), e))?;
+    let mut digests = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (file, digest) = parse_manifest_line(line)
+            .ok_or_else(|| format!("Malformed line {} in manifest {}", i + 1, path.display()))?;
+        let file = match path.parent() {
+            Some(dir) => dir.join(file),
+            None => file,
+        };
+        digests.insert(manifest_key(&file), digest);
+    }
+    Ok(digests)
+}
+
+// Answers from a manifest where it can and hashes everything else with
+// `fallback`. The manifest must have been made with the same algorithm.
+pub struct ManifestHasher {
+    digests: HashMap<PathBuf, String>,
+    fallback: Arc<dyn ContentHasher>,
+}
+
+impl ManifestHasher {
+    pub fn new(digests: HashMap<PathBuf, String>, fallback: Arc<dyn ContentHasher>) -> Self {
+        ManifestHasher { digests, fallback }
+    }
+}
+
+impl ContentHasher for ManifestHasher {
+    fn name(&self) -> &'static str {
+        self.fallback.name()
+    }
+
+    fn hash_file(&self, path: &Path) -> io::Result<String> {
+        match self.digests.get(&manifest_key(path)) {
+            Some(digest) => Ok(digest.clone()),
+            None => self.fallback.hash_file(path),
+        }
+    }
+
+    fn digester(&self, path: &Path) -> Option<Box<dyn Digester>> {
+        if self.digests.contains_key(&manifest_key(path)) {
+            return None;
+        }
+        self.fallback.digester(path)
+    }
+
+    fn record(&self, path: &Path, digest: &str) -> io::Result<()> {
+        self.fallback.record(path, digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_hash::HashAlgorithm;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_manifest_line() {
+        assert_eq!(
+            parse_manifest_line("abc123  dir/doc.pdf"),
+            Some((PathBuf::from("dir/doc.pdf"), "abc123".to_string()))
+        );
+        assert_eq!(
+            parse_manifest_line("ABC123 *doc (1).pdf"),
+            Some((PathBuf::from("doc (1).pdf"), "abc123".to_string()))
+        );
+        assert_eq!(
+            parse_manifest_line("\\abc123  a\\\\b\\nc"),
+            Some((PathBuf::from("a\\b\nc"), "abc123".to_string()))
+        );
+        assert_eq!(parse_manifest_line("not a manifest line"), None);
+        assert_eq!(parse_manifest_line("abc123"), None);
+    }
+
+    #[test]
+    fn test_write_then_read_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let a = temp_dir.path().join("a.pdf");
+        let b = temp_dir.path().join("we\\ird.pdf");
+        let entries = vec![(a.clone(), "aa".to_string()), (b.clone(), "bb".to_string())];
+
+        let manifest = temp_dir.path().join("out.b3");
+        let mut out = fs::File::create(&manifest).unwrap();
+        write_manifest(&mut out, &entries).unwrap();
+
+        let digests = read_manifest(&manifest).unwrap();
+        assert_eq!(digests.get(&a), Some(&"aa".to_string()));
+        assert_eq!(digests.get(&b), Some(&"bb".to_string()));
+    }
+
+    #[test]
+    fn test_relative_names_resolve_against_manifest_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let manifest = temp_dir.path().join("backup.b3");
+        fs::write(&manifest, "aa  ./a.pdf\nbb *sub/b.pdf\n").unwrap();
+
+        let digests = read_manifest(&manifest).unwrap();
+        let dir = std::path::absolute(temp_dir.path()).unwrap();
+        assert_eq!(digests.get(&dir.join("./a.pdf")), Some(&"aa".to_string()));
+        assert_eq!(digests.get(&dir.join("sub/b.pdf")), Some(&"bb".to_string()));
+    }
+
+    #[test]
+    fn test_manifest_hasher_falls_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let listed = temp_dir.path().join("listed.pdf");
+        let unlisted = temp_dir.path().join("unlisted.pdf");
+        fs::write(&listed, b"x").unwrap();
+        fs::write(&unlisted, b"x").unwrap();
+
+        let digests = HashMap::from([(listed.clone(), "from-manifest".to_string())]);
+        let hasher = ManifestHasher::new(digests, HashAlgorithm::Blake3.hasher());
+
+        assert_eq!(hasher.hash_file(&listed).unwrap(), "from-manifest");
+        assert_eq!(hasher.hash_file(&unlisted).unwrap(), blake3::hash(b"x").to_hex().to_string());
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{render_actions, Action, Deduplicator, MyResult};
+
+// Suffixes browsers append to a download until it completes:
+// Firefox writes `report.pdf.part`, Chrome writes `report.pdf.crdownload`.
+pub(crate) const PARTIAL_SUFFIXES: &[&str] = &[".part", ".crdownload"];
+
+fn complete_path(partial: &Path, suffix: &str) -> Option<PathBuf> {
+    let partial_str = partial.to_str()?;
+    let at = partial_str.len().checked_sub(suffix.len())?;
+    partial_str.get(at..)?
+        .eq_ignore_ascii_case(suffix)
+        .then(|| PathBuf::from(&partial_str[..at]))
+}
+
+// The complete file a partial download belongs to, if `path` is one.
+pub(crate) fn partial_download_target(path: &Path) -> Option<PathBuf> {
+    PARTIAL_SUFFIXES.iter().find_map(|suffix| complete_path(path, suffix))
+}
+
+pub fn process_partial_downloads(dir: &str, ext: &str) -> MyResult<String> {
+    Ok(render_actions(&plan_partial_downloads(dir, ext)?))
+}
+
+// The partial downloads of `ext` files in `dir` that a default scan would
+// plan for.
+pub fn plan_partial_downloads(dir: &str, ext: &str) -> MyResult<Vec<Action>> {
+    let dedup = Deduplicator::builder().root(dir).filetype(ext).build()?;
+    Ok(dedup.partial_downloads(Path::new(dir), ext)?.0)
+}
+
+// Leftover partial-download files are removed in favor of the complete file
+// they belong to. A partial file with no complete file next to it may still be
+// downloading, so it is only reported. Only a regular file counts as complete.
+pub(crate) fn plan_partials(partials: Vec<PathBuf>) -> Vec<Action> {
+    let mut result: Vec<Action> = vec![];
+    for partial in partials {
+        match partial_download_target(&partial) {
+            Some(complete) if fs::symlink_metadata(&complete).is_ok_and(|m| m.is_file()) => {
+                result.push(Action::Comment(
+                    format!("{} partial download {}", "-".repeat(30), partial.display())
+                ));
+                let reason = format!("partial download of {}", complete.display());
+                result.push(Action::remove(partial, Some(reason)));
+            }
+            _ => result.push(Action::Comment(
+                format!("partial download with no complete file: {}", partial.display())
+            )),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_partial_with_complete_file_is_removed() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        fs::write(dir_path.join("report.pdf"), b"complete").unwrap();
+        fs::write(dir_path.join("report.pdf.part"), b"comp").unwrap();
+        fs::write(dir_path.join("report.pdf.crdownload"), b"co").unwrap();
+
+        let result = process_partial_downloads(dir_path.to_str().unwrap(), ".pdf").unwrap();
+
+        assert!(result.contains("rm '"));
+        assert!(result.contains("report.pdf.part' # partial download of"));
+        assert!(result.contains("report.pdf.crdownload' # partial download of"));
+    }
+
+    #[test]
+    fn test_partial_without_complete_file_is_only_reported() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        fs::write(dir_path.join("report.pdf.part"), b"comp").unwrap();
+
+        let result = process_partial_downloads(dir_path.to_str().unwrap(), ".pdf").unwrap();
+
+        assert!(result.contains("no complete file"));
+        assert!(!result.contains("rm "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_is_not_a_complete_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        fs::write(dir_path.join("elsewhere.bin"), b"complete").unwrap();
+        std::os::unix::fs::symlink(dir_path.join("elsewhere.bin"), dir_path.join("report.pdf")).unwrap();
+        fs::write(dir_path.join("report.pdf.part"), b"comp").unwrap();
+
+        let result = process_partial_downloads(dir_path.to_str().unwrap(), ".pdf").unwrap();
+
+        assert!(result.contains("no complete file"));
+        assert!(!result.contains("rm "));
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::error::copy_io_error;
+use crate::file_hash::{ContentHasher, Digester, Snapshot};
+use crate::file_util::{file_id, is_rotational, open_noatime};
+use crate::throttle::{self, finished_reading, throttle, Throttle};
+
+// Bytes read at a time, and how many read chunks may wait for each hashing
+// thread before the readers block.
+const CHUNK_SIZE: usize = 1 << 20;
+const QUEUE_DEPTH: usize = 8;
+
+// Readers without a limit: enough to keep an SSD or a network share busy.
+const DEFAULT_IO_THREADS: usize = 4;
+
+enum Message {
+    Start(usize, Box<dyn Digester>),
+    Data(usize, Vec<u8>),
+    End(usize),
+    Failed(usize, io::Error),
+    // The hasher reads the file itself, with `hash_file`
+    Whole(usize),
+}
+
+// Hashes files with a pool of reader threads feeding a separate pool of
+// hashing threads through bounded queues, so slow disks and busy CPUs can be
+// sized independently. Files are read in inode order, which follows their
+// layout on disk on most file systems, and only one file at a time is read
+// from a spinning disk. The threads are paced like the thread that runs it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HashPipeline {
+    // Threads reading files; 4 by default
+    pub io_threads: Option<usize>,
+    // Threads hashing what was read; one per CPU by default
+    pub hash_threads: Option<usize>,
+}
+
+impl HashPipeline {
+    // The digest of each of `files`, in the same order. Names of one file
+    // are read once.
+    pub fn run(&self, files: &[PathBuf], hasher: &dyn ContentHasher) -> Vec<io::Result<String>> {
+        if files.is_empty() {
+            return vec![];
+        }
+        let ids: Vec<Option<(u64, u64)>> = files
+            .iter()
+            .map(|path| fs::metadata(path).ok().and_then(|m| file_id(&m)))
+            .collect();
+
+        // The first name of each file is read; the others share its digest
+        let mut first_name: HashMap<(u64, u64), usize> = HashMap::new();
+        let mut order: Vec<usize> = (0..files.len())
+            .filter(|&i| match ids[i] {
+                Some(id) => *first_name.entry(id).or_insert(i) == i,
+                None => true,
+            })
+            .collect();
+        order.sort_by_key(|&i| (ids[i].is_none(), ids[i]));
+
+        let disks: HashMap<u64, Mutex<()>> = ids
+            .iter()
+            .flatten()
+            .map(|&(dev, _)| dev)
+            .filter(|&dev| is_rotational(dev))
+            .map(|dev| (dev, Mutex::new(())))
+            .collect();
+
+        let io_threads = self.io_threads.unwrap_or(DEFAULT_IO_THREADS).clamp(1, order.len());
+        let hash_threads = self.hash_threads.unwrap_or_else(num_cpus::get).clamp(1, order.len());
+        let (senders, receivers): (Vec<SyncSender<Message>>, Vec<Receiver<Message>>) =
+            (0..hash_threads).map(|_| mpsc::sync_channel(QUEUE_DEPTH)).unzip();
+
+        let next_file = AtomicUsize::new(0);
+        let next_worker = AtomicUsize::new(0);
+        let mut digests: Vec<Option<io::Result<String>>> = files.iter().map(|_| None).collect();
+        let pacing = throttle::current();
+        thread::scope(|scope| {
+            let workers: Vec<_> = receivers
+                .into_iter()
+                .map(|receiver| {
+                    let pacing = pacing.clone();
+                    scope.spawn(move || {
+                        Throttle::adopt(pacing);
+                        hash_messages(receiver, files, hasher)
+                    })
+                })
+                .collect();
+            thread::scope(|readers| {
+                for _ in 0..io_threads {
+                    readers.spawn(|| {
+                        Throttle::adopt(pacing.clone());
+                        while let Some(&i) = order.get(next_file.fetch_add(1, Ordering::Relaxed)) {
+                            let worker = &senders[next_worker.fetch_add(1, Ordering::Relaxed) % senders.len()];
+                            let disk = ids[i].and_then(|(dev, _)| disks.get(&dev));
+                            read_file(i, files, hasher, disk, worker);
+                        }
+                    });
+                }
+            });
+            drop(senders);
+            for worker in workers {
+                let done = worker.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+                for (i, digest) in done {
+                    digests[i] = Some(digest);
+                }
+            }
+        });
+
+        // Other names get a copy of the result; the first name takes it over
+        let copies: Vec<Option<io::Result<String>>> = (0..files.len())
+            .map(|i| {
+                let first = ids[i].map_or(i, |id| first_name[&id]);
+                (first != i).then(|| match &digests[first] {
+                    Some(Ok(digest)) => Ok(digest.clone()),
+                    Some(Err(e)) => Err(copy_io_error(e)),
+                    None => Err(io::Error::other("file was not hashed")),
+                })
+            })
+            .collect();
+        copies
+            .into_iter()
+            .zip(digests)
+            .map(|(copy, digest)| match copy {
+                Some(copy) => copy,
+                None => digest.unwrap_or_else(|| Err(io::Error::other("file was not hashed"))),
+            })
+            .collect()
+    }
+}
+
+// Send the bytes of `files[i]` to a hashing thread, holding the disk it is
+// on for the duration if that is a spinning one.
+fn read_file(
+    i: usize,
+    files: &[PathBuf],
+    hasher: &dyn ContentHasher,
+    disk: Option<&Mutex<()>>,
+    worker: &SyncSender<Message>,
+) {
+    let Some(digester) = hasher.digester(&files[i]) else {
+        let _ = worker.send(Message::Whole(i));
+        return;
+    };
+    let _disk = disk.map(|lock| lock.lock().unwrap_or_else(|e| e.into_inner()));
+    let mut file = match open_noatime(&files[i]) {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = worker.send(Message::Failed(i, e));
+            return;
+        }
+    };
+    let snapshot = match Snapshot::of(&file) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            let _ = worker.send(Message::Failed(i, e));
+            return;
+        }
+    };
+    if worker.send(Message::Start(i, digester)).is_err() {
+        return;
+    }
+    let mut total = 0;
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        match (&mut file).take(CHUNK_SIZE as u64).read_to_end(&mut chunk) {
+            Ok(0) => {
+                finished_reading(&file);
+                let done = match snapshot.verify(&file, total) {
+                    Ok(()) => Message::End(i),
+                    Err(e) => Message::Failed(i, e),
+                };
+                let _ = worker.send(done);
+                return;
+            }
+            Ok(n) => {
+                throttle(n);
+                total += n as u64;
+                if worker.send(Message::Data(i, chunk)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                let _ = worker.send(Message::Failed(i, e));
+                return;
+            }
+        }
+    }
+}
+
+// Hash what the readers send until they are all done.
+fn hash_messages(
+    receiver: Receiver<Message>,
+    files: &[PathBuf],
+    hasher: &dyn ContentHasher,
+) -> Vec<(usize, io::Result<String>)> {
+    let mut open: HashMap<usize, Box<dyn Digester>> = HashMap::new();
+    let mut done = vec![];
+    for message in receiver {
+        match message {
+            Message::Start(i, digester) => {
+                open.insert(i, digester);
+            }
+            Message::Data(i, chunk) => {
+                if let Some(digester) = open.get_mut(&i) {
+                    digester.update(&chunk);
+                }
+            }
+            Message::End(i) => {
+                if let Some(digester) = open.remove(&i) {
+                    let digest = digester.finish();
+                    done.push((i, hasher.record(&files[i], &digest).map(|_| digest)));
+                }
+            }
+            Message::Failed(i, e) => {
+                open.remove(&i);
+                done.push((i, Err(e)));
+            }
+            Message::Whole(i) => done.push((i, hasher.hash_file(&files[i]))),
+        }
+    }
+    done
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ChangedDuringScan;
+    use crate::file_hash::HashAlgorithm;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_pipeline_matches_hash_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut files = vec![];
+        for (name, size) in [("empty", 0), ("small", 10), ("chunked", 3 * CHUNK_SIZE + 7)] {
+            let path = temp_dir.path().join(name);
+            fs::write(&path, (0..size).map(|i| (i % 251) as u8).collect::<Vec<u8>>()).unwrap();
+            files.push(path);
+        }
+        let linked = temp_dir.path().join("linked");
+        fs::hard_link(&files[2], &linked).unwrap();
+        files.push(linked);
+        files.push(temp_dir.path().join("missing"));
+
+        let pipeline = HashPipeline { io_threads: Some(2), hash_threads: Some(3) };
+        for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha256, HashAlgorithm::Xxh3] {
+            let hasher = algorithm.hasher();
+            let digests = pipeline.run(&files, hasher.as_ref());
+            for (path, digest) in files.iter().zip(&digests).take(4) {
+                assert_eq!(digest.as_ref().unwrap(), &hasher.hash_file(path).unwrap());
+            }
+            assert_eq!(digests[4].as_ref().unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+
+        // Hashers without a digester hash whole files on the hashing threads
+        let custom = |path: &std::path::Path| -> io::Result<String> { Ok(path.display().to_string()) };
+        let digests = pipeline.run(&files[..2], &custom);
+        assert_eq!(digests[1].as_ref().unwrap(), &files[1].display().to_string());
+
+        // Every name of a file that changed is reported as such
+        let changing = |_: &std::path::Path| -> io::Result<String> { Err(ChangedDuringScan::error()) };
+        for digest in pipeline.run(&files[2..4], &changing) {
+            let e = digest.unwrap_err();
+            assert!(e.get_ref().is_some_and(|e| e.is::<ChangedDuringScan>()));
+        }
+    }
+}
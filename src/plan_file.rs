@@ -0,0 +1,541 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::error::DedupError;
+use crate::file_hash::{ContentHasher, HashAlgorithm};
+use crate::file_util::modified_ns;
+use crate::journal::{escape, unescape};
+use crate::partial::partial_download_target;
+use crate::preserve::Preserve;
+use crate::{Action, Applier, DedupReport, MyResult};
+
+pub const PLAN_HEADER: &str = "# file-dup plan v1";
+
+const INSTRUCTIONS: &str = "\
+# Change the verb at the start of a line to decide what happens to that file:
+#   keep   leave the file; with \"=> NAME\" it is renamed to NAME
+#   rm     move the file into quarantine, where `file-dup undo` can restore it
+#   trash  move the file to the desktop trash
+#   skip   leave the file alone and do not check it
+# Apply re-checks the size, mtime and digest of every file that is not
+# skipped, and leaves a group untouched if any of them changed.";
+
+// What to do with one file of a plan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verb {
+    Keep,
+    Rm,
+    Trash,
+    Skip,
+}
+
+impl fmt::Display for Verb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Verb::Keep => "keep",
+            Verb::Rm => "rm",
+            Verb::Trash => "trash",
+            Verb::Skip => "skip",
+        };
+        f.pad(name)
+    }
+}
+
+impl FromStr for Verb {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Verb::Keep),
+            "rm" => Ok(Verb::Rm),
+            "trash" => Ok(Verb::Trash),
+            "skip" => Ok(Verb::Skip),
+            _ => Err(format!("Unknown verb '{}' (expected keep, rm, trash or skip)", s)),
+        }
+    }
+}
+
+// One file of a plan and the state it was in when the plan was made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanEntry {
+    pub verb: Verb,
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub digest: String,
+    pub path: PathBuf,
+    pub rename_to: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanGroup {
+    pub title: String,
+    pub entries: Vec<PlanEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanFile {
+    pub algorithm: HashAlgorithm,
+    // Metadata a kept file renamed over another takes from the group
+    pub preserve: Preserve,
+    pub groups: Vec<PlanGroup>,
+}
+
+fn plan_entry(verb: Verb, path: &Path, digest: String) -> io::Result<PlanEntry> {
+    let metadata = fs::metadata(path)?;
+    Ok(PlanEntry {
+        verb,
+        size: metadata.len(),
+        mtime_ns: modified_ns(&metadata)?,
+        digest,
+        path: path.to_path_buf(),
+        rename_to: None,
+    })
+}
+
+// Split off the first whitespace-separated field of `line`.
+fn next_field(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+    if end == 0 {
+        return None;
+    }
+    Some((&line[..end], line[end..].trim_start()))
+}
+
+// A path field, with the raw bytes escaped as in the journal. "=>" and
+// spaces at either end are escaped too, so neither the " => " separator nor
+// the edges of the field are ambiguous.
+fn path_field(path: &Path) -> String {
+    let text = escape(path).replace("=>", "=\\x3e");
+    let body = text.trim_matches(' ');
+    if body.is_empty() {
+        return "\\x20".repeat(text.len());
+    }
+    let leading = text.len() - text.trim_start_matches(' ').len();
+    let trailing = text.len() - text.trim_end_matches(' ').len();
+    format!("{}{}{}", "\\x20".repeat(leading), body, "\\x20".repeat(trailing))
+}
+
+impl PlanEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let (verb, rest) = next_field(line)?;
+        let (size, rest) = next_field(rest)?;
+        let (mtime_ns, rest) = next_field(rest)?;
+        let (digest, rest) = next_field(rest)?;
+        if rest.is_empty() {
+            return None;
+        }
+        let (path, rename_to) = match rest.split_once(" => ") {
+            Some((path, to)) => (path, Some(unescape(to)?)),
+            None => (rest, None),
+        };
+        Some(PlanEntry {
+            verb: verb.parse().ok()?,
+            size: size.parse().ok()?,
+            mtime_ns: mtime_ns.parse().ok()?,
+            digest: digest.to_string(),
+            path: unescape(path)?,
+            rename_to,
+        })
+    }
+
+    // Why the file no longer matches the plan, if it does not.
+    fn staleness(&self, hasher: &dyn ContentHasher) -> Option<String> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Some(format!("{} no longer exists", self.path.display())),
+        };
+        if metadata.len() != self.size {
+            return Some(format!("{} changed size", self.path.display()));
+        }
+        if modified_ns(&metadata).ok() != Some(self.mtime_ns) {
+            return Some(format!("{} was modified", self.path.display()));
+        }
+        match hasher.hash_file(&self.path) {
+            Ok(digest) if digest == self.digest => None,
+            Ok(_) => Some(format!("{} has different content", self.path.display())),
+            Err(e) => Some(format!("{} cannot be hashed: {}", self.path.display(), e)),
+        }
+    }
+}
+
+impl fmt::Display for PlanEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<5} {} {} {} {}", self.verb, self.size, self.mtime_ns, self.digest, path_field(&self.path))?;
+        if let Some(to) = &self.rename_to {
+            write!(f, " => {}", path_field(to))?;
+        }
+        Ok(())
+    }
+}
+
+impl PlanGroup {
+    fn has_changes(&self) -> bool {
+        self.entries.iter().any(|e| matches!(e.verb, Verb::Rm | Verb::Trash) || e.rename_to.is_some())
+    }
+
+    // Why this group cannot be applied as it stands, if it cannot.
+    pub fn check(&self, hasher: &dyn ContentHasher) -> Result<(), String> {
+        let active: Vec<&PlanEntry> = self.entries.iter().filter(|e| e.verb != Verb::Skip).collect();
+        let removed: HashSet<&Path> = active
+            .iter()
+            .filter(|e| matches!(e.verb, Verb::Rm | Verb::Trash))
+            .map(|e| e.path.as_path())
+            .collect();
+        if !removed.is_empty() && !active.iter().any(|e| e.verb == Verb::Keep) {
+            return Err("nothing in the group would be kept".to_string());
+        }
+        for entry in &active {
+            if entry.rename_to.is_some() && entry.verb != Verb::Keep {
+                return Err(format!("only a kept file can be renamed: {}", entry.path.display()));
+            }
+            if let Some(to) = &entry.rename_to
+                && to.symlink_metadata().is_ok()
+                && !removed.contains(to.as_path())
+            {
+                return Err(format!("{} would be overwritten", to.display()));
+            }
+        }
+        match active.iter().find_map(|e| e.staleness(hasher)) {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+
+    // Removals first, then renames. A kept file taking the name of one that
+    // is removed is promoted over it, so that name never goes missing, and
+    // then given the metadata `preserve` asks for.
+    pub fn apply<F>(&self, applier: &mut Applier, preserve: Preserve, mut applied: F) -> MyResult<()>
+    where
+        F: FnMut(&PlanEntry),
+    {
+        let targets: HashSet<&Path> = self.entries
+            .iter()
+            .filter(|e| e.verb == Verb::Keep)
+            .filter_map(|e| e.rename_to.as_deref())
+            .collect();
+
+        // Read before anything moves: removed members and replaced files go away
+        let members: Vec<&Path> = self.entries
+            .iter()
+            .filter(|e| e.verb != Verb::Skip)
+            .map(|e| e.path.as_path())
+            .collect();
+        let mut metadata: HashMap<&Path, Vec<Action>> = HashMap::new();
+        for entry in self.entries.iter().filter(|e| e.verb == Verb::Keep) {
+            if let Some(to) = &entry.rename_to {
+                let original = if members.contains(&to.as_path()) { to } else { &entry.path };
+                let actions = preserve.actions(original, &members, to)
+                    .map_err(|e| format!("Failed to read metadata of {}: {}", original.display(), e))?;
+                metadata.insert(&entry.path, actions);
+            }
+        }
+
+        let mut replaced: HashMap<&Path, &PlanEntry> = HashMap::new();
+        for entry in &self.entries {
+            match entry.verb {
+                Verb::Rm | Verb::Trash if targets.contains(entry.path.as_path()) => {
+                    replaced.insert(&entry.path, entry);
+                    continue;
+                }
+                Verb::Rm => applier.remove(&entry.path)?,
+                Verb::Trash => applier.trash(&entry.path)?,
+                Verb::Keep | Verb::Skip => continue,
+            }
+            applied(entry);
+        }
+        for entry in self.entries.iter().filter(|e| e.verb == Verb::Keep) {
+            if let Some(to) = &entry.rename_to {
+                match replaced.get(to.as_path()) {
+                    Some(old) => {
+                        applier.promote(&entry.path, to, old.verb == Verb::Trash)?;
+                        applied(old);
+                    }
+                    None => applier.rename(&entry.path, to)?,
+                }
+                applier.apply(&metadata[entry.path.as_path()], |_| {})?;
+                applied(entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Groups that were applied and groups that were left alone, with the reason.
+#[derive(Debug, Default)]
+pub struct PlanOutcome {
+    pub applied: usize,
+    pub refused: Vec<(String, String)>,
+}
+
+impl PlanFile {
+    // Every duplicate group and partial download of `report`, with the verbs
+    // the report's actions imply.
+    pub fn from_report(
+        report: &DedupReport,
+        hasher: &dyn ContentHasher,
+        algorithm: HashAlgorithm,
+    ) -> (Self, Vec<DedupError>) {
+        let mut plan = PlanFile { algorithm, preserve: Preserve::default(), groups: vec![] };
+        let mut errors = vec![];
+
+        for group in &report.groups {
+            let mut verbs: HashMap<&Path, (Verb, Option<PathBuf>)> = HashMap::new();
+            for action in &group.actions {
+                match action {
+                    Action::Remove { path, .. } => { verbs.insert(path, (Verb::Rm, None)); }
+                    Action::Rename { from, to } => { verbs.insert(from, (Verb::Keep, Some(to.clone()))); }
+                    _ => {}
+                }
+            }
+            let entries: Result<Vec<PlanEntry>, DedupError> = group.members
+                .iter()
+                .map(|member| {
+                    let (verb, rename_to) = verbs.remove(member.path.as_path()).unwrap_or((Verb::Keep, None));
+                    let mut entry = plan_entry(verb, &member.path, member.digest.clone())
+                        .map_err(|e| DedupError::io(&member.path, e))?;
+                    entry.rename_to = rename_to;
+                    Ok(entry)
+                })
+                .collect();
+            match entries {
+                Ok(entries) => plan.groups.push(PlanGroup { title: group.base.display().to_string(), entries }),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        for action in &report.partial_downloads {
+            let Action::Remove { path, .. } = action else { continue };
+            let Some(complete) = partial_download_target(path) else { continue };
+            let entries: Result<Vec<PlanEntry>, DedupError> = [(Verb::Keep, &complete), (Verb::Rm, path)]
+                .into_iter()
+                .map(|(verb, file)| {
+                    let digest = hasher.hash_file(file).map_err(|e| DedupError::hash(file, e))?;
+                    plan_entry(verb, file, digest).map_err(|e| DedupError::io(file, e))
+                })
+                .collect();
+            match entries {
+                Ok(entries) => plan.groups.push(PlanGroup { title: format!("partial download of {}", complete.display()), entries }),
+                Err(e) => errors.push(e),
+            }
+        }
+        (plan, errors)
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let algorithm = self.algorithm.hasher();
+        writeln!(out, "{}", PLAN_HEADER)?;
+        writeln!(out, "# hash: {}", algorithm.name())?;
+        if !self.preserve.is_empty() {
+            writeln!(out, "# preserve: {}", self.preserve)?;
+        }
+        writeln!(out, "{}", INSTRUCTIONS)?;
+        for group in &self.groups {
+            writeln!(out)?;
+            writeln!(out, "group {}", group.title)?;
+            for entry in &group.entries {
+                writeln!(out, "{}", entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, line)| line) != Some(PLAN_HEADER) {
+            return Err("not a file-dup plan (missing header)".to_string());
+        }
+        let mut plan = PlanFile { algorithm: HashAlgorithm::default(), preserve: Preserve::default(), groups: vec![] };
+        for (i, line) in lines {
+            if let Some(name) = line.strip_prefix("# hash: ") {
+                plan.algorithm = name.trim().parse()?;
+            } else if let Some(names) = line.strip_prefix("# preserve: ") {
+                plan.preserve = names.parse()?;
+            } else if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            } else if let Some(title) = line.strip_prefix("group ") {
+                plan.groups.push(PlanGroup { title: title.to_string(), entries: vec![] });
+            } else {
+                let entry = PlanEntry::parse(line).ok_or_else(|| format!("line {}: not a plan line: {}", i + 1, line))?;
+                let group = plan.groups.last_mut().ok_or_else(|| format!("line {}: entry outside a group", i + 1))?;
+                group.entries.push(entry);
+            }
+        }
+        Ok(plan)
+    }
+
+    pub fn read(path: &Path) -> MyResult<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read plan {}: {}", path.display(), e))?;
+        Ok(Self::parse(&text).map_err(|e| format!("Invalid plan {}: {}", path.display(), e))?)
+    }
+
+    // Check every group against the files on disk without changing anything.
+    pub fn check(&self) -> PlanOutcome {
+        let hasher = self.algorithm.hasher();
+        let mut outcome = PlanOutcome::default();
+        for group in self.groups.iter().filter(|g| g.has_changes()) {
+            match group.check(hasher.as_ref()) {
+                Ok(()) => outcome.applied += 1,
+                Err(reason) => outcome.refused.push((group.title.clone(), reason)),
+            }
+        }
+        outcome
+    }
+
+    // Apply every group that still matches the files on disk; the others are
+    // refused and left untouched.
+    pub fn apply<F>(&self, applier: &mut Applier, mut applied: F) -> MyResult<PlanOutcome>
+    where
+        F: FnMut(&PlanEntry),
+    {
+        let hasher = self.algorithm.hasher();
+        let mut outcome = PlanOutcome::default();
+        for group in self.groups.iter().filter(|g| g.has_changes()) {
+            match group.check(hasher.as_ref()) {
+                Ok(()) => {
+                    group.apply(applier, self.preserve, &mut applied)?;
+                    outcome.applied += 1;
+                }
+                Err(reason) => outcome.refused.push((group.title.clone(), reason)),
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_util::set_modified_ns;
+    use crate::journal::Journal;
+    use crate::Deduplicator;
+    use tempfile::TempDir;
+
+    fn write_plan(dir: &Path) -> PlanFile {
+        let dedup = Deduplicator::builder().root(dir).filetype(".pdf").build().unwrap();
+        let report = dedup.run().unwrap();
+        let (plan, errors) = PlanFile::from_report(&report, dedup.hasher(), HashAlgorithm::Blake3);
+        assert!(errors.is_empty());
+        plan
+    }
+
+    #[test]
+    fn test_plan_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("doc.pdf"), b"same").unwrap();
+        fs::write(temp_dir.path().join("doc (1).pdf"), b"same").unwrap();
+
+        let plan = write_plan(temp_dir.path());
+        let mut text = vec![];
+        plan.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+
+        assert!(text.contains("\nkeep  4 "));
+        assert!(text.contains("\nrm    4 "));
+        assert_eq!(PlanFile::parse(&text).unwrap(), plan);
+        assert!(PlanFile::parse("keep 1 2 abc /x").is_err());
+    }
+
+    #[test]
+    fn test_entry_paths_round_trip() {
+        let mut names = vec![
+            PathBuf::from("/d/a => b.pdf"),
+            PathBuf::from("  leading.pdf"),
+            PathBuf::from("trailing.pdf  "),
+            PathBuf::from("/d/we\\ird\tname\n.pdf"),
+            PathBuf::from("   "),
+        ];
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            names.push(PathBuf::from(std::ffi::OsStr::from_bytes(b"/d/caf\xe9.pdf")));
+        }
+        for path in &names {
+            for rename_to in [None, Some(PathBuf::from("/d/x => y.pdf")), Some(path.clone())] {
+                let entry = PlanEntry {
+                    verb: Verb::Keep,
+                    size: 1,
+                    mtime_ns: 2,
+                    digest: "ab".to_string(),
+                    path: path.clone(),
+                    rename_to,
+                };
+                assert_eq!(PlanEntry::parse(&entry.to_string()), Some(entry));
+            }
+        }
+    }
+
+    #[test]
+    fn test_edited_plan_refuses_changed_groups() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        for (name, content) in [("a.pdf", "same"), ("a (1).pdf", "same"), ("b.pdf", "same"), ("b (1).pdf", "same")] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let mut plan = write_plan(dir);
+        assert_eq!(plan.groups.len(), 2);
+
+        // Flip the removal in group "a" to the trash and change a file of group "b"
+        let a = plan.groups.iter_mut().find(|g| g.title.ends_with("a.pdf")).unwrap();
+        a.entries[1].verb = Verb::Trash;
+        fs::write(dir.join("b (1).pdf"), b"edit").unwrap();
+
+        let journal = Journal::open(&dir.join("journal.log")).unwrap();
+        let mut applier = Applier::new(&dir.join("quarantine"), journal).unwrap().with_trash(&dir.join("Trash"));
+        let outcome = plan.apply(&mut applier, |_| {}).unwrap();
+
+        assert_eq!(outcome.applied, 1);
+        assert_eq!(outcome.refused.len(), 1);
+        assert!(outcome.refused[0].1.contains("b (1).pdf"));
+        assert!(dir.join("Trash/files/a (1).pdf").exists());
+        assert!(dir.join("b (1).pdf").exists());
+    }
+
+    #[test]
+    fn test_preserve_times_with_extra_identical_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        fs::write(dir.join("doc.pdf"), b"a").unwrap();
+        fs::write(dir.join("doc (1).pdf"), b"a").unwrap();
+        fs::write(dir.join("doc (2).pdf"), b"bb").unwrap();
+        set_modified_ns(&dir.join("doc (1).pdf"), 1_000_000_000_000).unwrap();
+
+        let mut plan = write_plan(dir);
+        plan.preserve = Preserve { mode: false, times: true, xattrs: false };
+        let group = &plan.groups[0];
+        assert_eq!(group.entries.iter().filter(|e| e.verb == Verb::Rm).count(), 2);
+
+        let journal = Journal::open(&dir.join("journal.log")).unwrap();
+        let mut applier = Applier::new(&dir.join("quarantine"), journal).unwrap();
+        let outcome = plan.apply(&mut applier, |_| {}).unwrap();
+
+        assert_eq!(outcome.applied, 1);
+        assert_eq!(fs::read(dir.join("doc.pdf")).unwrap(), b"bb");
+        assert!(!dir.join("doc (1).pdf").exists());
+        assert!(!dir.join("doc (2).pdf").exists());
+        assert_eq!(modified_ns(&fs::metadata(dir.join("doc.pdf")).unwrap()).unwrap(), 1_000_000_000_000);
+    }
+
+    #[test]
+    fn test_group_must_keep_a_file() {
+        let group = PlanGroup {
+            title: "g".to_string(),
+            entries: vec![PlanEntry {
+                verb: Verb::Rm,
+                size: 1,
+                mtime_ns: 0,
+                digest: "x".to_string(),
+                path: PathBuf::from("/nonexistent/a.pdf"),
+                rename_to: None,
+            }],
+        };
+        let hasher = HashAlgorithm::Blake3.hasher();
+        assert_eq!(group.check(hasher.as_ref()), Err("nothing in the group would be kept".to_string()));
+    }
+}
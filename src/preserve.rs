@@ -0,0 +1,130 @@
+use std::{
+    fmt, fs, io,
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_util::{file_mode, modified_ns, xattrs};
+use crate::Action;
+
+// Metadata a promoted keeper takes over from the group: the permissions and
+// extended attributes of the file it replaces, and the earliest mtime of any
+// member. Written "mode,times,xattrs" on the command line and in profiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Preserve {
+    pub mode: bool,
+    pub times: bool,
+    pub xattrs: bool,
+}
+
+impl FromStr for Preserve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut preserve = Preserve::default();
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "mode" => preserve.mode = true,
+                "times" => preserve.times = true,
+                "xattrs" => preserve.xattrs = true,
+                _ => return Err(format!("Unknown metadata '{}' (expected mode, times or xattrs)", name)),
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+impl fmt::Display for Preserve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = [(self.mode, "mode"), (self.times, "times"), (self.xattrs, "xattrs")]
+            .into_iter()
+            .filter_map(|(on, name)| on.then_some(name))
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl TryFrom<String> for Preserve {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Preserve> for String {
+    fn from(preserve: Preserve) -> Self {
+        preserve.to_string()
+    }
+}
+
+impl Preserve {
+    pub fn is_empty(&self) -> bool {
+        *self == Preserve::default()
+    }
+
+    // The actions that give `survivor` the mode and extended attributes of
+    // `original` and the earliest mtime among `members`. Attributes are set
+    // before the mode, which may make the file read-only.
+    pub fn actions(&self, original: &Path, members: &[&Path], survivor: &Path) -> io::Result<Vec<Action>> {
+        let mut actions = vec![];
+        if self.xattrs {
+            for (name, value) in xattrs(original)? {
+                actions.push(Action::SetXattr { path: survivor.to_path_buf(), name, value });
+            }
+        }
+        if self.times {
+            let mut earliest: Option<i64> = None;
+            for member in members {
+                let mtime_ns = modified_ns(&fs::metadata(member)?)?;
+                earliest = Some(earliest.map_or(mtime_ns, |e| e.min(mtime_ns)));
+            }
+            if let Some(mtime_ns) = earliest {
+                actions.push(Action::Touch { path: survivor.to_path_buf(), mtime_ns });
+            }
+        }
+        if self.mode
+            && let Some(mode) = file_mode(&fs::metadata(original)?)
+        {
+            actions.push(Action::Chmod { path: survivor.to_path_buf(), mode });
+        }
+        Ok(actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_util::set_modified_ns;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_and_display() {
+        let preserve: Preserve = "times, mode".parse().unwrap();
+        assert_eq!(preserve, Preserve { mode: true, times: true, xattrs: false });
+        assert_eq!(preserve.to_string(), "mode,times");
+        assert!("owner".parse::<Preserve>().is_err());
+        assert!("".parse::<Preserve>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_actions_carry_earliest_time_and_original_mode() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.path().join("doc.pdf");
+        let newer = temp_dir.path().join("doc (1).pdf");
+        fs::write(&original, b"old").unwrap();
+        fs::write(&newer, b"new").unwrap();
+        set_modified_ns(&original, 1_000_000_000_000).unwrap();
+
+        let preserve = Preserve { mode: true, times: true, xattrs: false };
+        let actions = preserve.actions(&original, &[&original, &newer], &original).unwrap();
+        let mode = file_mode(&fs::metadata(&original).unwrap()).unwrap();
+        assert_eq!(actions, vec![
+            Action::Touch { path: original.clone(), mtime_ns: 1_000_000_000_000 },
+            Action::Chmod { path: original.clone(), mode },
+        ]);
+    }
+}
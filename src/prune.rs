@@ -0,0 +1,182 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::error::DedupError;
+use crate::file_hash::ContentHasher;
+use crate::manifest::{hash_files, read_manifest};
+use crate::{files_recursive, Action, MyResult};
+
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub actions: Vec<Action>,
+    pub errors: Vec<DedupError>,
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|m| m.len())
+}
+
+// Remove every file under `target` whose content already exists in
+// `reference`, which is either a directory or a checksum manifest. Only
+// target files are ever proposed for removal; target files that live inside
+// the reference tree are left out entirely, as are manifest entries that
+// list files inside the target.
+pub fn plan_prune(
+    reference: &Path,
+    target: &Path,
+    ext: Option<&str>,
+    hasher: &dyn ContentHasher,
+) -> MyResult<PruneReport> {
+    if !target.is_dir() {
+        return Err(format!("Path is not a directory: {}", target.display()).into());
+    }
+    let reference_root = fs::canonicalize(reference)
+        .map_err(|e| format!("Invalid reference {}: {}", reference.display(), e))?;
+    let target_root = fs::canonicalize(target)
+        .map_err(|e| format!("Invalid target {}: {}", target.display(), e))?;
+
+    let mut targets: Vec<PathBuf> = files_recursive(target)?
+        .into_iter()
+        .filter(|p| ext.is_none_or(|ext| p.to_string_lossy().ends_with(ext)))
+        .filter(|p| {
+            fs::canonicalize(p).is_ok_and(|c| !c.starts_with(&reference_root))
+        })
+        .collect();
+    targets.sort();
+
+    let mut report = PruneReport::default();
+
+    // digest -> reference path
+    let mut known: HashMap<String, PathBuf> = HashMap::new();
+    if reference_root.is_dir() {
+        // Only reference files of a size some target file has can match.
+        let target_sizes: HashSet<u64> = targets.iter().filter_map(|p| file_size(p)).collect();
+        let candidates: Vec<PathBuf> = files_recursive(reference)?
+            .into_iter()
+            .filter(|p| file_size(p).is_some_and(|s| target_sizes.contains(&s)))
+            .collect();
+        let (entries, errors) = hash_files(&candidates, hasher);
+        report.errors.extend(errors);
+        for (path, digest) in entries {
+            known.entry(digest).or_insert(path);
+        }
+
+        let reference_sizes: HashSet<u64> = candidates.iter().filter_map(|p| file_size(p)).collect();
+        targets.retain(|p| file_size(p).is_some_and(|s| reference_sizes.contains(&s)));
+    } else {
+        // An entry for a target file would match that file itself
+        for (path, digest) in read_manifest(reference)? {
+            let inside_target = fs::canonicalize(&path).is_ok_and(|c| c.starts_with(&target_root));
+            if !inside_target {
+                known.entry(digest).or_insert(path);
+            }
+        }
+    }
+
+    let (mut entries, errors) = hash_files(&targets, hasher);
+    report.errors.extend(errors);
+    entries.sort();
+    for (path, digest) in entries {
+        if let Some(reference_path) = known.get(&digest) {
+            report.actions.push(Action::Comment(
+                format!("{} {}", path.display(), digest)
+            ));
+            let reason = format!("matches {}", reference_path.display());
+            report.actions.push(Action::remove(path, Some(reason)));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_hash::HashAlgorithm;
+    use crate::manifest::write_manifest;
+    use tempfile::TempDir;
+
+    fn removed(report: &PruneReport) -> Vec<PathBuf> {
+        report.actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::Remove { path, .. } => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prune_against_directory() {
+        let reference = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        fs::create_dir(reference.path().join("nested")).unwrap();
+        fs::write(reference.path().join("nested/backup.pdf"), b"backed up").unwrap();
+        fs::write(target.path().join("copy.pdf"), b"backed up").unwrap();
+        fs::write(target.path().join("new.pdf"), b"not backed up").unwrap();
+
+        let hasher = HashAlgorithm::Blake3.hasher();
+        let report = plan_prune(reference.path(), target.path(), None, hasher.as_ref()).unwrap();
+
+        assert_eq!(removed(&report), vec![target.path().join("copy.pdf")]);
+        assert!(report.actions[1].to_string().contains("nested/backup.pdf"));
+        assert!(reference.path().join("nested/backup.pdf").exists());
+    }
+
+    #[test]
+    fn test_prune_against_manifest() {
+        let reference = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        fs::write(target.path().join("copy.pdf"), b"backed up").unwrap();
+        fs::write(target.path().join("copy.txt"), b"backed up").unwrap();
+
+        let manifest = reference.path().join("backup.b3");
+        let entries = vec![(
+            PathBuf::from("/mnt/backup/doc.pdf"),
+            blake3::hash(b"backed up").to_hex().to_string(),
+        )];
+        write_manifest(&mut fs::File::create(&manifest).unwrap(), &entries).unwrap();
+
+        let hasher = HashAlgorithm::Blake3.hasher();
+        let report = plan_prune(&manifest, target.path(), Some(".pdf"), hasher.as_ref()).unwrap();
+
+        assert_eq!(removed(&report), vec![target.path().join("copy.pdf")]);
+        assert!(report.actions[1].to_string().ends_with("# matches /mnt/backup/doc.pdf"));
+    }
+
+    #[test]
+    fn test_manifest_of_target_prunes_nothing() {
+        let target = TempDir::new().unwrap();
+        fs::write(target.path().join("a.pdf"), b"same").unwrap();
+        fs::write(target.path().join("b.pdf"), b"same").unwrap();
+        fs::write(target.path().join("c.pdf"), b"other").unwrap();
+
+        let hasher = HashAlgorithm::Blake3.hasher();
+        let files = files_recursive(target.path()).unwrap();
+        let (entries, errors) = hash_files(&files, hasher.as_ref());
+        assert!(errors.is_empty());
+        let manifest_dir = TempDir::new().unwrap();
+        let manifest = manifest_dir.path().join("target.b3");
+        write_manifest(&mut fs::File::create(&manifest).unwrap(), &entries).unwrap();
+
+        let report = plan_prune(&manifest, target.path(), None, hasher.as_ref()).unwrap();
+
+        assert_eq!(removed(&report), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn test_reference_inside_target_is_never_pruned() {
+        let target = TempDir::new().unwrap();
+        let reference = target.path().join("backup");
+        fs::create_dir(&reference).unwrap();
+        fs::write(reference.join("doc.pdf"), b"same").unwrap();
+        fs::write(target.path().join("doc.pdf"), b"same").unwrap();
+
+        let hasher = HashAlgorithm::Blake3.hasher();
+        let report = plan_prune(&reference, target.path(), None, hasher.as_ref()).unwrap();
+
+        assert_eq!(removed(&report), vec![target.path().join("doc.pdf")]);
+    }
+}
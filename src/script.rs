@@ -0,0 +1,204 @@
+use std::{fs, path::Path};
+
+use crate::error::DedupError;
+use crate::file_hash::ContentHasher;
+use crate::action::quote_path;
+use crate::partial::partial_download_target;
+use crate::{Action, DedupReport};
+
+// The command-line tool whose output matches a hasher's digests.
+fn checksum_tool(algorithm: &str) -> Option<&'static str> {
+    match algorithm {
+        "blake3" => Some("b3sum"),
+        "sha256" => Some("sha256sum"),
+        "xxh3" => Some("xxh128sum"),
+        _ => None,
+    }
+}
+
+// The start of a generated script: what produced it, strict mode, and the
+// `fdup_unchanged` guard every group's actions run behind.
+pub fn render_script_header(arguments: &[String], algorithm: &str) -> String {
+    let now = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%z");
+    let arguments = arguments.join(" ").replace('\n', "\\n");
+    let mut lines = vec![
+        "#!/usr/bin/env bash".to_string(),
+        format!("# Generated by file-dup {} at {}", env!("CARGO_PKG_VERSION"), now),
+        format!("# Arguments: {}", arguments),
+        format!("# Hash: {}", algorithm),
+        "set -eu".to_string(),
+        String::new(),
+    ];
+    match checksum_tool(algorithm) {
+        Some(tool) => {
+            lines.push(format!("# Succeeds if FILE still has SIZE bytes and, when {tool} is installed, DIGEST."));
+            lines.push("fdup_unchanged() {".to_string());
+            lines.push("    [ -f \"$1\" ] || return 1".to_string());
+            lines.push("    [ \"$(wc -c < \"$1\" | tr -d ' ')\" = \"$2\" ] || return 1".to_string());
+            lines.push(format!("    if command -v {tool} > /dev/null 2>&1; then"));
+            lines.push(format!("        [ \"$({tool} < \"$1\" | cut -d ' ' -f 1)\" = \"$3\" ] || return 1"));
+            lines.push("    fi".to_string());
+            lines.push("}".to_string());
+        }
+        None => {
+            lines.push("# Succeeds if FILE still has SIZE bytes.".to_string());
+            lines.push("fdup_unchanged() {".to_string());
+            lines.push("    [ -f \"$1\" ] && [ \"$(wc -c < \"$1\" | tr -d ' ')\" = \"$2\" ]".to_string());
+            lines.push("}".to_string());
+        }
+    }
+    lines.push("fdup_stale=0".to_string());
+    lines.join("\n")
+}
+
+// The end of a generated script: a run that skipped groups exits with 2.
+pub fn render_script_footer() -> String {
+    [
+        "if [ \"$fdup_stale\" -gt 0 ]; then",
+        "    echo \"$fdup_stale group(s) changed since this script was made and were skipped\" >&2",
+        "    exit 2",
+        "fi",
+    ].join("\n")
+}
+
+fn guard(path: &Path, digest: &str) -> Result<String, DedupError> {
+    let size = fs::metadata(path).map_err(|e| DedupError::io(path, e))?.len();
+    Ok(format!("fdup_unchanged {} {} {}", quote_path(path), size, digest))
+}
+
+// Comments as they are; the changes of a group only run if every file it
+// names is still the size and digest it was planned with.
+fn guarded_block(title: &Path, actions: &[Action], guards: &[String]) -> String {
+    let mut lines: Vec<String> = actions
+        .iter()
+        .filter(|a| matches!(a, Action::Comment(_)))
+        .map(|a| a.to_string())
+        .collect();
+    let changes: Vec<String> = actions
+        .iter()
+        .filter(|a| !matches!(a, Action::Comment(_)))
+        .map(|a| format!("    {}", a))
+        .collect();
+    if !changes.is_empty() {
+        lines.push(format!("if {}; then", guards.join(" && ")));
+        lines.extend(changes);
+        lines.push("else".to_string());
+        lines.push(format!(
+            "    printf 'Skipped %s: files changed since this script was made\\n' {} >&2",
+            quote_path(title)
+        ));
+        lines.push("    fdup_stale=$((fdup_stale + 1))".to_string());
+        lines.push("fi".to_string());
+    }
+    lines.join("\n")
+}
+
+// The report's groups and partial downloads as guarded shell. A group whose
+// files cannot be examined now is left out and returned as an error.
+pub fn render_guarded(report: &DedupReport, hasher: &dyn ContentHasher) -> (String, Vec<DedupError>) {
+    let mut blocks = vec![];
+    let mut errors = vec![];
+
+    for group in &report.groups {
+        let guards: Result<Vec<String>, DedupError> = group.members
+            .iter()
+            .map(|member| guard(&member.path, &member.digest))
+            .collect();
+        match guards {
+            Ok(guards) => blocks.push(guarded_block(&group.base, &group.actions, &guards)),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    for action in &report.partial_downloads {
+        let Action::Remove { path, .. } = action else {
+            blocks.push(action.to_string());
+            continue;
+        };
+        let guards: Result<Vec<String>, DedupError> = std::iter::once(path.clone())
+            .chain(partial_download_target(path))
+            .map(|file| {
+                let digest = hasher.hash_file(&file).map_err(|e| DedupError::hash(&file, e))?;
+                guard(&file, &digest)
+            })
+            .collect();
+        match guards {
+            Ok(guards) => blocks.push(guarded_block(path, std::slice::from_ref(action), &guards)),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    let blocks: Vec<String> = blocks.into_iter().filter(|b| !b.is_empty()).collect();
+    (blocks.join("\n"), errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Deduplicator;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    fn script_for(dir: &Path) -> String {
+        let dedup = Deduplicator::builder().root(dir).filetype(".pdf").build().unwrap();
+        let report = dedup.run().unwrap();
+        let (body, errors) = render_guarded(&report, dedup.hasher());
+        assert!(errors.is_empty());
+        [render_script_header(&["file-dup".to_string()], "blake3"), body, render_script_footer()].join("\n")
+    }
+
+    #[test]
+    fn test_guarded_script_layout() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("doc.pdf"), b"same").unwrap();
+        fs::write(temp_dir.path().join("doc (1).pdf"), b"same").unwrap();
+
+        let script = script_for(temp_dir.path());
+        assert!(script.starts_with("#!/usr/bin/env bash\n# Generated by file-dup "));
+        assert!(script.contains("\nset -eu\n"));
+        assert!(script.contains("doc (1).pdf' 4 "));
+        assert!(script.contains("; then\n    rm '"));
+        assert!(script.ends_with("    exit 2\nfi"));
+    }
+
+    #[test]
+    fn test_stale_group_is_skipped_when_run() {
+        if Command::new("bash").arg("--version").output().is_err() {
+            return;
+        }
+        let temp_dir = TempDir::new().unwrap();
+        let copy = temp_dir.path().join("doc (1).pdf");
+        fs::write(temp_dir.path().join("doc.pdf"), b"same").unwrap();
+        fs::write(&copy, b"same").unwrap();
+        let script = script_for(temp_dir.path());
+
+        fs::write(&copy, b"changed").unwrap();
+        let output = Command::new("bash").arg("-c").arg(&script).output().unwrap();
+
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Skipped"));
+        assert!(copy.exists());
+    }
+
+    #[test]
+    fn test_file_names_cannot_run_commands() {
+        if Command::new("bash").arg("--version").output().is_err() {
+            return;
+        }
+        let temp_dir = TempDir::new().unwrap();
+        let marker = temp_dir.path().join("injected");
+        let name = "it's $(touch injected) `touch injected` \"x\"";
+        let base = temp_dir.path().join(format!("{name}.pdf"));
+        let copy = temp_dir.path().join(format!("{name} (1).pdf"));
+        fs::write(&base, b"same").unwrap();
+        fs::write(&copy, b"same").unwrap();
+        let script = script_for(temp_dir.path());
+
+        let output = Command::new("bash").arg("-c").arg(&script).current_dir(temp_dir.path()).output().unwrap();
+
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(!marker.exists());
+        assert!(base.exists());
+        assert!(!copy.exists());
+    }
+}
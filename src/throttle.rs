@@ -0,0 +1,221 @@
+use std::{
+    cell::RefCell,
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_util::{drop_page_cache, lower_priority, open_noatime};
+
+// A read rate in bytes per second, written like rsync's `--bwlimit`: a number
+// with an optional K, M or G suffix (powers of 1024), e.g. "50M".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Bandwidth(pub u64);
+
+const UNITS: [(char, u64); 3] = [('G', 1 << 30), ('M', 1 << 20), ('K', 1 << 10)];
+
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid bandwidth '{}' (expected e.g. 500K, 50M or 1G)", s);
+        let (digits, scale) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some(suffix) if suffix.is_ascii_alphabetic() => {
+                let (_, scale) = UNITS.iter().find(|(unit, _)| *unit == suffix).ok_or_else(invalid)?;
+                (&s[..s.len() - 1], *scale)
+            }
+            _ => (s, 1),
+        };
+        let amount: u64 = digits.parse().map_err(|_| invalid())?;
+        match amount.checked_mul(scale) {
+            Some(0) | None => Err(invalid()),
+            Some(rate) => Ok(Bandwidth(rate)),
+        }
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match UNITS.iter().find(|(_, scale)| self.0.is_multiple_of(*scale)) {
+            Some((unit, scale)) => write!(f, "{}{}", self.0 / scale, unit),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl TryFrom<String> for Bandwidth {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Bandwidth> for String {
+    fn from(bandwidth: Bandwidth) -> Self {
+        bandwidth.to_string()
+    }
+}
+
+// How the reads of one scan are paced: the rate they share, and whether the
+// scan runs as a background job, at the lowest CPU and I/O priority and
+// without leaving the files it reads in the page cache.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    // Bytes per second; 0 when unlimited
+    rate: u64,
+    // When the bytes read so far are paid for at `rate`
+    paid_until: Mutex<Option<Instant>>,
+    background: bool,
+}
+
+thread_local! {
+    // The throttle reads on this thread are paced by, if any
+    static CURRENT: RefCell<Option<Arc<Throttle>>> = const { RefCell::new(None) };
+}
+
+// Reads on the thread that entered a throttle are paced by it until this is
+// dropped.
+pub struct PacedReads {
+    previous: Option<Arc<Throttle>>,
+}
+
+impl Drop for PacedReads {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+impl Throttle {
+    pub fn new(limit: Option<Bandwidth>, background: bool) -> Self {
+        Throttle { rate: limit.map_or(0, |limit| limit.0), paid_until: Mutex::new(None), background }
+    }
+
+    // Pace the reads of the calling thread by this throttle. Its priority is
+    // left alone: only the threads a scan starts run in the background.
+    pub fn enter(self: &Arc<Self>) -> PacedReads {
+        let previous = CURRENT.with(|current| current.borrow_mut().replace(self.clone()));
+        PacedReads { previous }
+    }
+
+    // Pace a thread a scan started like the thread that started it, whose
+    // `current()` this is. The thread ends with the scan, so its priority
+    // can be lowered for good.
+    pub(crate) fn adopt(throttle: Option<Arc<Throttle>>) {
+        if let Some(throttle) = &throttle
+            && throttle.background
+        {
+            // Advice, like the page cache hint: the scan works either way
+            let _ = lower_priority();
+        }
+        CURRENT.with(|current| *current.borrow_mut() = throttle);
+    }
+
+    // Account for `bytes` just read, sleeping as long as the rate requires.
+    // Reads are paid for one after another, so concurrent readers share it.
+    fn pay(&self, bytes: usize) {
+        if self.rate == 0 || bytes == 0 {
+            return;
+        }
+        let wait = {
+            let mut paid_until = self.paid_until.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let start = paid_until.map_or(now, |t| t.max(now));
+            let end = start + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+            *paid_until = Some(end);
+            end - now
+        };
+        thread::sleep(wait);
+    }
+}
+
+// The throttle of the calling thread, to hand to the threads it starts.
+pub(crate) fn current() -> Option<Arc<Throttle>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// Account for `bytes` just read on this thread.
+pub(crate) fn throttle(bytes: usize) {
+    CURRENT.with(|current| {
+        if let Some(throttle) = &*current.borrow() {
+            throttle.pay(bytes);
+        }
+    });
+}
+
+// Done reading `file` for hashing.
+pub(crate) fn finished_reading(file: &File) {
+    if current().is_some_and(|throttle| throttle.background) {
+        drop_page_cache(file);
+    }
+}
+
+// A file opened without updating its access time, whose reads are paced by
+// the calling thread's throttle.
+pub(crate) struct PacedFile {
+    file: File,
+}
+
+impl PacedFile {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        Ok(PacedFile { file: open_noatime(path)? })
+    }
+}
+
+impl Read for PacedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        throttle(n);
+        Ok(n)
+    }
+}
+
+impl Seek for PacedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for PacedFile {
+    fn drop(&mut self) {
+        finished_reading(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display_bandwidth() {
+        assert_eq!("50M".parse::<Bandwidth>().unwrap(), Bandwidth(50 << 20));
+        assert_eq!("500k".parse::<Bandwidth>().unwrap(), Bandwidth(500 << 10));
+        assert_eq!("4096".parse::<Bandwidth>().unwrap(), Bandwidth(4096));
+        assert_eq!(Bandwidth(50 << 20).to_string(), "50M");
+        assert_eq!(Bandwidth(1000).to_string(), "1000");
+        for invalid in ["", "M", "0", "5X", "-1M", "99999999999G"] {
+            assert!(invalid.parse::<Bandwidth>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_entered_throttle_paces_reads_until_dropped() {
+        let throttle = Arc::new(Throttle::new(Some(Bandwidth(1 << 20)), false));
+        {
+            let _paced = throttle.enter();
+            assert!(current().is_some());
+            let start = Instant::now();
+            super::throttle(3 << 18);
+            assert!(start.elapsed() >= Duration::from_millis(700));
+        }
+        assert!(current().is_none());
+    }
+}
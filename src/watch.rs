@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::{Action, Applier, Deduplicator, Journal, MyResult};
+
+pub struct WatchOptions {
+    pub dir: PathBuf,
+    // Decides which files are copies and which of them to keep, as `plan`
+    // would for the same directory
+    pub dedup: Deduplicator,
+    // How long a file must go without writes before it is compared
+    pub settle: Duration,
+    // Execute the plan instead of only logging it
+    pub apply: bool,
+    // Where applied removals are moved, and the journal that records them
+    pub quarantine: PathBuf,
+    pub journal: PathBuf,
+}
+
+impl WatchOptions {
+    // Quarantine in "<dir>/.file-dup-trash" with its journal alongside.
+    pub fn new(dir: PathBuf, dedup: Deduplicator, settle: Duration, apply: bool) -> Self {
+        let quarantine = dir.join(".file-dup-trash");
+        let journal = quarantine.join("journal.log");
+        WatchOptions { dir, dedup, settle, apply, quarantine, journal }
+    }
+}
+
+// Copies that have been written to recently, keyed by path.
+#[derive(Default)]
+pub struct Pending {
+    last_write: HashMap<PathBuf, Instant>,
+}
+
+impl Pending {
+    pub fn touch(&mut self, path: PathBuf, now: Instant) {
+        self.last_write.insert(path, now);
+    }
+
+    // Remove and return the files that have been quiet for at least `settle`.
+    pub fn take_quiescent(&mut self, now: Instant, settle: Duration) -> Vec<PathBuf> {
+        let ready: Vec<PathBuf> = self.last_write
+            .iter()
+            .filter(|(_, t)| now.duration_since(**t) >= settle)
+            .map(|(p, _)| p.clone())
+            .collect();
+        for path in &ready {
+            self.last_write.remove(path);
+        }
+        ready
+    }
+}
+
+fn record(log: &mut dyn Write, message: &str) {
+    let now = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%z");
+    // A failing log must not stop the watcher; report it on stderr instead.
+    if let Err(e) = writeln!(log, "{now} {message}").and_then(|_| log.flush()) {
+        eprintln!("Error: failed to write log: {}", e);
+    }
+}
+
+fn handle_copy(
+    copy: &Path,
+    options: &WatchOptions,
+    applier: Option<&mut Applier>,
+    log: &mut dyn Write,
+) -> MyResult<()> {
+    let dedup = &options.dedup;
+    let Some(ext) = dedup.copy_type(copy) else {
+        return Ok(());
+    };
+    let _paced = dedup.pace_reads();
+    // The files `plan` would see: excluded names, symlinks that are not
+    // followed and anything but regular files drop out here
+    let (files, _) = dedup.files_in(&options.dir, ext)?;
+    let Some(copy) = files.iter().find(|p| p.file_name() == copy.file_name()) else {
+        return Ok(());
+    };
+    let Some((base, _)) = dedup.base_of(copy, &files) else {
+        record(log, &format!("skipped {}: no base file", copy.display()));
+        return Ok(());
+    };
+    let actions = dedup.plan_group(&base, ext, &files)?
+        .map(|group| group.actions)
+        .unwrap_or_default();
+
+    if let Some(applier) = applier {
+        applier.apply(&actions, |action| record(log, &format!("applied: {action}")))?;
+    } else {
+        for action in actions.iter().filter(|a| !matches!(a, Action::Comment(_))) {
+            record(log, &format!("proposed: {action}"));
+        }
+    }
+    Ok(())
+}
+
+// Watch `options.dir` until the process is stopped. Numbered copies are
+// compared with their base file once they stop changing.
+pub fn watch(options: &WatchOptions, log: &mut dyn Write) -> MyResult<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .map_err(|e| format!("Failed to start watcher: {}", e))?;
+    watcher.watch(&options.dir, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("Failed to watch {}: {}", options.dir.display(), e))?;
+    record(log, &format!("watching {}", options.dir.display()));
+
+    let mut applier = if options.apply {
+        std::fs::create_dir_all(&options.quarantine)?;
+        let journal = Journal::open(&options.journal)?;
+        record(log, &format!("journal {}", journal.path().display()));
+        Some(Applier::new(&options.quarantine, journal)?)
+    } else {
+        None
+    };
+
+    let tick = Duration::from_millis(250).min(options.settle);
+    let mut pending = Pending::default();
+    loop {
+        match rx.recv_timeout(tick) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if options.dedup.copy_type(&path).is_some() {
+                            pending.touch(path, Instant::now());
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => record(log, &format!("watch error: {e}")),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err("Watcher stopped unexpectedly".into());
+            }
+        }
+
+        for copy in pending.take_quiescent(Instant::now(), options.settle) {
+            if let Err(e) = handle_copy(&copy, options, applier.as_mut(), log) {
+                record(log, &format!("error: {}: {}", copy.display(), e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeduplicatorBuilder, KeepPolicy};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn options(dir: &Path, builder: DeduplicatorBuilder) -> WatchOptions {
+        let dedup = builder.root(dir).build().unwrap();
+        WatchOptions::new(dir.to_path_buf(), dedup, Duration::from_secs(1), false)
+    }
+
+    #[test]
+    fn test_copy_type_and_base() {
+        let files = [PathBuf::from("/d/doc.pdf"), PathBuf::from("/d/my doc.pdf")];
+        let dedup = Deduplicator::builder().filetype(".pdf").build().unwrap();
+        let base = |copy: &str| dedup.base_of(Path::new(copy), &files).map(|(base, _)| base);
+        assert_eq!(base("/d/doc (1).pdf"), Some(PathBuf::from("/d/doc.pdf")));
+        assert_eq!(base("/d/my doc (12).pdf"), Some(PathBuf::from("/d/my doc.pdf")));
+        assert_eq!(base("/d/doc (1) (1).pdf"), Some(PathBuf::from("/d/doc.pdf")));
+        assert_eq!(base("/e/doc (1).pdf"), None);
+        assert_eq!(dedup.copy_type(Path::new("/d/doc (1).pdf")), Some(".pdf"));
+        assert_eq!(dedup.copy_type(Path::new("/d/doc.pdf")), None);
+        assert_eq!(dedup.copy_type(Path::new("/d/doc(1).pdf")), None);
+        assert_eq!(dedup.copy_type(Path::new("/d/doc (1).txt")), None);
+    }
+
+    #[test]
+    fn test_pending_waits_for_quiet_period() {
+        let settle = Duration::from_secs(5);
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        pending.touch(PathBuf::from("a (1).pdf"), start);
+        pending.touch(PathBuf::from("b (1).pdf"), start + Duration::from_secs(3));
+
+        assert!(pending.take_quiescent(start + Duration::from_secs(4), settle).is_empty());
+        assert_eq!(
+            pending.take_quiescent(start + Duration::from_secs(6), settle),
+            vec![PathBuf::from("a (1).pdf")]
+        );
+        assert_eq!(
+            pending.take_quiescent(start + Duration::from_secs(9), settle),
+            vec![PathBuf::from("b (1).pdf")]
+        );
+    }
+
+    #[test]
+    fn test_handle_copy_logs_and_applies() {
+        let temp_dir = TempDir::new().unwrap();
+        let base = temp_dir.path().join("doc.pdf");
+        let copy = temp_dir.path().join("doc (1).pdf");
+        fs::write(&base, b"same").unwrap();
+        fs::write(&copy, b"same").unwrap();
+
+        let options = options(temp_dir.path(), Deduplicator::builder().filetype(".pdf"));
+        let mut log: Vec<u8> = vec![];
+        handle_copy(&copy, &options, None, &mut log).unwrap();
+        assert!(String::from_utf8_lossy(&log).contains("proposed: rm"));
+        assert!(copy.exists());
+
+        fs::create_dir(&options.quarantine).unwrap();
+        let journal = Journal::open(&options.journal).unwrap();
+        let mut applier = Applier::new(&options.quarantine, journal).unwrap();
+        let mut log: Vec<u8> = vec![];
+        handle_copy(&copy, &options, Some(&mut applier), &mut log).unwrap();
+        assert!(String::from_utf8_lossy(&log).contains("applied: rm"));
+        assert!(!copy.exists());
+        assert!(options.quarantine.join("doc (1).pdf").exists());
+    }
+
+    #[test]
+    fn test_handle_copy_follows_plan_settings() {
+        let temp_dir = TempDir::new().unwrap();
+        let base = temp_dir.path().join("doc.pdf");
+        let copy = temp_dir.path().join("doc_v2.pdf");
+        fs::write(&base, b"old").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(&copy, b"newer").unwrap();
+
+        let builder = Deduplicator::builder()
+            .filetype(".pdf")
+            .copy_pattern(r"_v\d+")
+            .keep(KeepPolicy::Base);
+        let options = options(temp_dir.path(), builder);
+        let mut log: Vec<u8> = vec![];
+        handle_copy(&copy, &options, None, &mut log).unwrap();
+        let log = String::from_utf8_lossy(&log);
+        assert!(log.contains("proposed: rm") && log.contains("doc_v2.pdf"));
+        assert!(!log.contains("mv"));
+    }
+}
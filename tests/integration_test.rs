@@ -1,4 +1,5 @@
 #![allow(deprecated)]
+#![allow(clippy::needless_borrows_for_generic_args)]
 
 use assert_cmd::Command;
 use predicates::prelude::*;
@@ -205,3 +206,679 @@ fn test_output_format_with_comments() {
     // Should have hash comments in the output
     assert!(stdout.contains("# ------"));
 }
+
+#[test]
+fn test_images_reports_near_duplicates_without_deleting() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+
+    for (name, width) in [("photo.png", 200), ("photo-small.png", 100)] {
+        let img = image::ImageBuffer::from_fn(width, width / 2, |x, _| {
+            image::Luma([(x * 255 / width) as u8])
+        });
+        img.save(dir_path.join(name)).unwrap();
+    }
+
+    let output = Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--filetype", ".png", "--images"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Found 1 groups of near-duplicate images"))
+        .get_output()
+        .stdout
+        .clone();
+
+    let stdout = String::from_utf8(output).unwrap();
+    assert!(stdout.contains("photo-small.png (100x50) distance"));
+    assert!(!stdout.contains("rm "));
+}
+
+#[test]
+fn test_equivalent_archives_are_duplicates() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+
+    for (name, stored) in [("book.epub", false), ("book (1).epub", true)] {
+        let mut writer = zip::ZipWriter::new(File::create(dir_path.join(name)).unwrap());
+        let mut options = zip::write::SimpleFileOptions::default();
+        if stored {
+            options = options.compression_method(zip::CompressionMethod::Stored);
+        }
+        writer.start_file("chapter.xhtml", options).unwrap();
+        writer.write_all(b"<p>chapter</p>").unwrap();
+        writer.finish().unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--filetype", ".epub"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("book (1).epub' #"))
+        .stdout(predicate::str::contains("(same archive members)"))
+        .stdout(predicate::str::contains("mv ").not());
+}
+
+#[test]
+fn test_watch_rejects_missing_directory() {
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["watch", "/nonexistent/directory/that/does/not/exist"])
+        .assert()
+        .failure()
+        .code(1)
+        .stderr(predicate::str::contains("not a directory"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unreadable_file_exits_with_distinct_code() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+
+    File::create(dir_path.join("doc.pdf")).unwrap();
+    std::os::unix::fs::symlink(dir_path.join("missing"), dir_path.join("doc (1).pdf")).unwrap();
+    {
+        let mut f = File::create(dir_path.join("other.pdf")).unwrap();
+        f.write_all(b"same").unwrap();
+        let mut f = File::create(dir_path.join("other (1).pdf")).unwrap();
+        f.write_all(b"same").unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--follow-symlinks"])
+        .assert()
+        .failure()
+        .code(2)
+        .stdout(predicate::str::contains("other (1).pdf"))
+        .stderr(predicate::str::contains("1 file(s) could not be processed"))
+        .stderr(predicate::str::contains("doc (1).pdf"));
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--follow-symlinks", "--fail-fast"])
+        .assert()
+        .failure()
+        .code(1);
+}
+
+#[test]
+fn test_sha256_hash_option() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+
+    for name in ["doc.pdf", "doc (1).pdf"] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(b"abc").unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--hash", "sha256"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+}
+
+#[test]
+fn test_hash_manifest_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+
+    for (name, content) in [("doc.pdf", "one"), ("doc (1).pdf", "two")] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+    }
+    let manifest = dir_path.join("out.b3");
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["hash", "--dir", dir_path.to_str().unwrap(), "--manifest", manifest.to_str().unwrap()])
+        .assert()
+        .success();
+
+    let text = std::fs::read_to_string(&manifest).unwrap();
+    let expected = format!("{}  {}", blake3::hash(b"one").to_hex(), dir_path.join("doc.pdf").display());
+    assert!(text.lines().any(|l| l == expected));
+
+    // Pretend the manifest says the files are identical: the run must trust it
+    let same = blake3::hash(b"one").to_hex().to_string();
+    let forged = text
+        .lines()
+        .map(|l| format!("{}{}", same, &l[l.find(' ').unwrap()..]))
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(&manifest, forged).unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--from-manifest", manifest.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("doc (1).pdf' #"))
+        .stdout(predicate::str::contains("mv ").not());
+}
+
+#[test]
+fn test_prune_against_reference_directory() {
+    let reference = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+
+    {
+        let mut f = File::create(reference.path().join("saved.pdf")).unwrap();
+        f.write_all(b"backed up").unwrap();
+        let mut f = File::create(target.path().join("download.pdf")).unwrap();
+        f.write_all(b"backed up").unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args([
+            "prune",
+            "--reference",
+            reference.path().to_str().unwrap(),
+            target.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("download.pdf' # matches"))
+        .stdout(predicate::str::contains("saved.pdf"));
+}
+
+#[test]
+fn test_prune_relative_manifest_of_target_from_elsewhere() {
+    let target = TempDir::new().unwrap();
+    let elsewhere = TempDir::new().unwrap();
+    std::fs::write(target.path().join("a.pdf"), b"same").unwrap();
+    std::fs::write(target.path().join("b.pdf"), b"same").unwrap();
+
+    // `b3sum *` run inside the target lists its own files by relative name
+    let digest = blake3::hash(b"same").to_hex().to_string();
+    let manifest = target.path().join("target.b3");
+    std::fs::write(&manifest, format!("{digest}  a.pdf\n{digest}  b.pdf\n")).unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .current_dir(elsewhere.path())
+        .args([
+            "prune",
+            "--reference",
+            manifest.to_str().unwrap(),
+            target.path().to_str().unwrap(),
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("rm ").not());
+
+    assert!(target.path().join("a.pdf").exists());
+    assert!(target.path().join("b.pdf").exists());
+}
+
+#[test]
+fn test_catalog_then_query() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let catalog = TempDir::new().unwrap();
+    let catalog_path = catalog.path().join("file-dup.db");
+
+    // Same content under unrelated names: not a copy group, but a catalog duplicate
+    for name in ["first.pdf", "second.pdf"] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(b"same").unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--catalog", catalog_path.to_str().unwrap()])
+        .assert()
+        .success();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["query", "--catalog", catalog_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 duplicate groups, 4 bytes reclaimable"))
+        .stdout(predicate::str::contains("first.pdf"))
+        .stdout(predicate::str::contains("second.pdf"));
+}
+
+#[test]
+fn test_undo_restores_journaled_actions() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let quarantine = dir_path.join(".file-dup-trash");
+    std::fs::create_dir(&quarantine).unwrap();
+    let base = dir_path.join("doc.pdf");
+    let copy = dir_path.join("doc (1).pdf");
+
+    // State after "rm doc.pdf; mv doc (1).pdf doc.pdf" was applied natively
+    File::create(quarantine.join("doc.pdf")).unwrap().write_all(b"old").unwrap();
+    File::create(&base).unwrap().write_all(b"newer").unwrap();
+    let journal = quarantine.join("journal.log");
+    let mut f = File::create(&journal).unwrap();
+    writeln!(f, "# file-dup journal v1").unwrap();
+    let digest = |content: &[u8]| blake3::hash(content).to_hex().to_string();
+    writeln!(f, "remove\t{}\t{}\t3\t{}", base.display(), quarantine.join("doc.pdf").display(), digest(b"old")).unwrap();
+    writeln!(f, "rename\t{}\t{}\t5\t{}", copy.display(), base.display(), digest(b"newer")).unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["undo", journal.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Undid 2 action(s)"));
+
+    assert_eq!(std::fs::read(&base).unwrap(), b"old");
+    assert_eq!(std::fs::read(&copy).unwrap(), b"newer");
+
+    // Undone entries are cut from the journal, so a second undo does nothing
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["undo", journal.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Undid 0 action(s)"));
+    assert_eq!(std::fs::read(&base).unwrap(), b"old");
+    assert_eq!(std::fs::read(&copy).unwrap(), b"newer");
+}
+
+#[test]
+fn test_plan_verify_apply_subcommands() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let plan_dir = TempDir::new().unwrap();
+    let plan_path = plan_dir.path().join("plan.sh");
+
+    for name in ["doc.pdf", "doc (1).pdf"] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(b"same").unwrap();
+    }
+
+    let output = Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["plan", "--dir", dir_path.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    std::fs::write(&plan_path, &output.stdout).unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["verify", plan_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("is valid: 1 action(s)"));
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["apply", plan_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("rm '"))
+        .stdout(predicate::str::contains("Journal written to"));
+
+    assert!(dir_path.join("doc.pdf").exists());
+    assert!(!dir_path.join("doc (1).pdf").exists());
+    assert!(plan_dir.path().join(".file-dup-trash/doc (1).pdf").exists());
+
+    // The copy is gone now, so the same plan is stale
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["verify", plan_path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("no longer exists"));
+}
+
+#[test]
+fn test_script_group_changed_after_planning_is_skipped() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let plan_dir = TempDir::new().unwrap();
+    let plan_path = plan_dir.path().join("plan.sh");
+    let quarantine = plan_dir.path().join("quarantine");
+
+    for name in ["doc.pdf", "doc (1).pdf", "other.pdf", "other (1).pdf"] {
+        std::fs::write(dir_path.join(name), b"same").unwrap();
+    }
+    let output = Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["plan", "--dir", dir_path.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    std::fs::write(&plan_path, &output.stdout).unwrap();
+
+    // Same size, different content
+    std::fs::write(dir_path.join("doc (1).pdf"), b"SAME").unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["verify", plan_path.to_str().unwrap()])
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains("1 group(s) valid, 1 stale"))
+        .stderr(predicate::str::contains("doc (1).pdf has different content"));
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["apply", "--quarantine", quarantine.to_str().unwrap(), plan_path.to_str().unwrap()])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("doc (1).pdf has different content"));
+
+    assert_eq!(std::fs::read(dir_path.join("doc (1).pdf")).unwrap(), b"SAME");
+    assert!(!quarantine.join("doc (1).pdf").exists());
+    assert!(!dir_path.join("other (1).pdf").exists());
+    assert!(quarantine.join("other (1).pdf").exists());
+}
+
+#[test]
+fn test_report_subcommand() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+
+    for name in ["doc.pdf", "doc (1).pdf", "doc (2).pdf", "other.pdf"] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(b"12345").unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["report", "--dir", dir_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files scanned:       4"))
+        .stdout(predicate::str::contains("duplicate groups:    1"))
+        .stdout(predicate::str::contains("reclaimable bytes:   10"));
+}
+
+#[test]
+fn test_ignore_case_matches_and_warns() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+
+    for name in ["Report.PDF", "report (1).pdf", "notes.pdf", "NOTES.pdf"] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(b"12345").unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["report", "--ignore-case", "--dir", dir_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files scanned:       4"))
+        .stdout(predicate::str::contains("files to remove:     1"))
+        .stderr(predicate::str::contains("Warning: "))
+        .stderr(predicate::str::contains("differ only by case"));
+}
+
+#[test]
+fn test_thread_options() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    std::fs::write(dir_path.join("doc.pdf"), b"same").unwrap();
+    std::fs::write(dir_path.join("doc (1).pdf"), b"same").unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["report", "--io-threads", "1", "--hash-threads", "2", "--dir", dir_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files to remove:     1"));
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["report", "--io-threads", "0", "--dir", dir_path.to_str().unwrap()])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_nice_and_bwlimit_pace_reads() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let data = vec![7u8; 512 * 1024];
+    std::fs::write(dir_path.join("doc.pdf"), &data).unwrap();
+    std::fs::write(dir_path.join("doc (1).pdf"), &data).unwrap();
+
+    // 1 MiB to read at 2 MiB/s
+    let started = std::time::Instant::now();
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["report", "--nice", "--bwlimit", "2M", "--dir", dir_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files to remove:     1"));
+    assert!(started.elapsed() >= std::time::Duration::from_millis(400));
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["report", "--bwlimit", "fast", "--dir", dir_path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid bandwidth"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fifo_and_symlink_are_skipped() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    std::fs::write(dir_path.join("doc.pdf"), b"same").unwrap();
+    let fifo = std::ffi::CString::new(dir_path.join("doc (1).pdf").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+    std::os::unix::fs::symlink(dir_path.join("doc.pdf"), dir_path.join("doc (2).pdf")).unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["report", "--dir", dir_path.to_str().unwrap()])
+        .timeout(std::time::Duration::from_secs(30))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("files scanned:       1"))
+        .stdout(predicate::str::contains("duplicate groups:    0"))
+        .stderr(predicate::str::contains("is not a regular file"))
+        .stderr(predicate::str::contains("is a symlink and was left alone"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fifo_partial_download_is_skipped() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    std::fs::write(dir_path.join("doc.pdf"), b"same").unwrap();
+    std::fs::write(dir_path.join("doc (1).pdf"), b"same").unwrap();
+    std::fs::write(dir_path.join("x.pdf"), b"x").unwrap();
+    let fifo = std::ffi::CString::new(dir_path.join("x.pdf.part").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap()])
+        .timeout(std::time::Duration::from_secs(30))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("doc (1).pdf"))
+        .stdout(predicate::str::contains("x.pdf.part").not())
+        .stderr(predicate::str::contains("x.pdf.part\" is not a regular file"));
+}
+
+#[test]
+fn test_excluded_partial_download_is_left_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    std::fs::write(dir_path.join("keep.pdf"), b"complete").unwrap();
+    std::fs::write(dir_path.join("keep.pdf.part"), b"comp").unwrap();
+    std::fs::write(dir_path.join("other.pdf"), b"complete").unwrap();
+    std::fs::write(dir_path.join("other.pdf.part"), b"comp").unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["--dir", dir_path.to_str().unwrap(), "--exclude", "keep*"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("other.pdf.part' # partial download of"))
+        .stdout(predicate::str::contains("keep.pdf.part").not());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_preserve_mode_when_promoting() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let base = dir_path.join("doc.pdf");
+    std::fs::write(&base, b"old").unwrap();
+    std::fs::set_permissions(&base, std::fs::Permissions::from_mode(0o640)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    std::fs::write(dir_path.join("doc (1).pdf"), b"newer").unwrap();
+
+    let output = Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["plan", "--preserve", "mode,times", "--dir", dir_path.to_str().unwrap()])
+        .output()
+        .unwrap();
+    let script = String::from_utf8(output.stdout).unwrap();
+    assert!(script.contains(&format!("    chmod 0640 '{}'", base.display())));
+    assert!(script.contains("    touch -m -d "));
+
+    let plan_path = dir_path.join("plan.sh");
+    std::fs::write(&plan_path, script).unwrap();
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["apply", plan_path.to_str().unwrap()])
+        .assert()
+        .success();
+    let metadata = std::fs::metadata(&base).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+    assert_eq!(metadata.len(), 5);
+}
+
+#[test]
+fn test_profile_from_config_with_cli_override() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let config_home = TempDir::new().unwrap();
+    let config_dir = config_home.path().join("file-dup");
+    std::fs::create_dir(&config_dir).unwrap();
+
+    for name in ["book.epub", "book (1).epub", "doc.pdf", "doc (1).pdf"] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(b"same").unwrap();
+    }
+    let mut f = File::create(config_dir.join("config.toml")).unwrap();
+    writeln!(f, "[profile.books]").unwrap();
+    writeln!(f, "roots = [{:?}]", dir_path.to_str().unwrap()).unwrap();
+    writeln!(f, "filetypes = [\".epub\"]").unwrap();
+    writeln!(f, "keep = \"base\"").unwrap();
+    writeln!(f, "ignore_case = true").unwrap();
+    writeln!(f, "nice = true").unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["--profile", "books"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("book (1).epub"))
+        .stdout(predicate::str::contains("doc (1).pdf").not());
+
+    // A flag replaces the profile's setting
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["--profile", "books", "--filetype", ".pdf"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("doc (1).pdf"))
+        .stdout(predicate::str::contains("book (1).epub").not());
+
+    // A --no- flag turns off a switch the profile turns on
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["config", "show", "--profile", "books", "--no-ignore-case"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("ignore_case = false"))
+        .stdout(predicate::str::contains("nice = true"));
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["config", "show", "--profile", "books", "--hash", "sha256"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("filetypes = [\".epub\"]"))
+        .stdout(predicate::str::contains("keep = \"base\""))
+        .stdout(predicate::str::contains("hash = \"sha256\""));
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["--profile", "music"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Unknown profile 'music'"));
+}
+
+#[test]
+fn test_editable_plan_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir_path = temp_dir.path();
+    let plan_dir = TempDir::new().unwrap();
+    let plan_path = plan_dir.path().join("plan.txt");
+
+    for name in ["a.pdf", "a (1).pdf", "b.pdf", "b (1).pdf"] {
+        let mut f = File::create(dir_path.join(name)).unwrap();
+        f.write_all(b"same").unwrap();
+    }
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["plan", "--dir", dir_path.to_str().unwrap(), "-o", plan_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Wrote 2 group(s)"));
+
+    // Decide to leave "b (1).pdf" alone
+    let plan = std::fs::read_to_string(&plan_path).unwrap();
+    assert!(plan.starts_with("# file-dup plan v1\n# hash: blake3\n"));
+    let edited: Vec<String> = plan
+        .lines()
+        .map(|line| match line.strip_prefix("rm ") {
+            Some(rest) if line.ends_with("b (1).pdf") => format!("skip{}", rest),
+            _ => line.to_string(),
+        })
+        .collect();
+    std::fs::write(&plan_path, edited.join("\n")).unwrap();
+
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["verify", plan_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 group(s) valid, 0 stale"));
+
+    // A file changed after planning: its group is refused
+    File::create(dir_path.join("a (1).pdf")).unwrap().write_all(b"changed").unwrap();
+    Command::cargo_bin("file-dup")
+        .unwrap()
+        .args(["apply", plan_path.to_str().unwrap()])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("a (1).pdf changed size"));
+
+    assert!(dir_path.join("a (1).pdf").exists());
+    assert!(dir_path.join("b (1).pdf").exists());
+}